@@ -0,0 +1,226 @@
+// Copyright 2017 dmg Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Converting disk images between formats.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use super::create::{created_path, image_key_arg, path_with_extension, Size, TempImagePath};
+use super::{hdiutil, output_with_progress, run, Progress, ProgressFn};
+
+format_enum! {
+    /// Image formats `hdiutil convert` can produce.
+    ///
+    /// Hybrid `UNIV` images can only be created from a folder, so unlike
+    /// [`FolderImageFormat`](../create/enum.FolderImageFormat.html) there is no such variant.
+    pub enum ConvertFormat {
+        /// UDIF read/write image.
+        UDRW,
+        /// UDIF read-only image.
+        UDRO,
+        /// UDIF ADC-compressed image.
+        UDCO,
+        /// UDIF zlib-compressed image.
+        UDZO,
+        /// UDIF bzip2-compressed image.
+        UDBZ,
+        /// UDIF lzfse-compressed image (macOS 10.11+ only).
+        ULFO,
+        /// UDIF lzma-compressed image (macOS 10.15+ only).
+        ULMO,
+        /// UDIF entire image with MD5 checksum.
+        UFBI,
+        /// DVD/CD-R master for export.
+        UDTO,
+        /// Sparse image, grows with content.
+        UDSP,
+        /// Sparse bundle image, grows with content.
+        UDSB,
+    }
+}
+
+/// Builder to convert an existing disk image into another format.
+#[must_use = "builders do nothing unless `convert()` or similar is called"]
+pub struct Convert {
+    image: PathBuf,
+    format: ConvertFormat,
+    overwrite: bool,
+    segment_size: Option<Size>,
+    align: Option<u64>,
+    progress: Option<Box<ProgressFn>>,
+    timeout: Option<Duration>,
+    image_keys: Vec<OsString>,
+    target_image_keys: Vec<OsString>,
+    library: Option<PathBuf>,
+    extra_args: Vec<OsString>,
+}
+
+impl Convert {
+    /// Creates a new builder converting `image` into the given format.
+    pub fn new<P: Into<PathBuf>>(image: P, format: ConvertFormat) -> Convert {
+        Convert {
+            image: image.into(),
+            format,
+            overwrite: false,
+            segment_size: None,
+            align: None,
+            progress: None,
+            timeout: None,
+            image_keys: Vec::new(),
+            target_image_keys: Vec::new(),
+            library: None,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Overwrite an existing file at the destination path.
+    pub fn overwrite(mut self) -> Convert {
+        self.overwrite = true;
+        self
+    }
+
+    /// Split the output into `.dmgpart` segments of at most `size`.
+    ///
+    /// Use [`segment_paths()`](fn.segment_paths.html) to find all of them afterwards.
+    pub fn segment_size<S: Into<Size>>(mut self, size: S) -> Convert {
+        self.segment_size = Some(size.into());
+        self
+    }
+
+    /// Align the data partition to `sectors` 512-byte sectors.
+    ///
+    /// The converted image may be rounded up in size to honour the alignment.
+    pub fn align(mut self, sectors: u64) -> Convert {
+        self.align = Some(sectors);
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if converting takes longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Convert {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    key_value_fn!("Set an image key for reading the source image", "-imagekey", image_key, image_keys);
+    key_value_fn!("Set an image key of the converted image such as `sparse-band-size`", "-tgtimagekey",
+        target_image_key, target_image_keys);
+    library_fn!(library);
+
+    extra_args_fns!(extra_args);
+
+    /// Call `on_progress` with progress updates while the image is converted.
+    pub fn on_progress<F: FnMut(Progress) + Send + 'static>(mut self, on_progress: F) -> Convert {
+        self.progress = Some(Box::new(on_progress));
+        self
+    }
+
+    pub(crate) fn build_command(&self, output: &Path) -> Command {
+        let mut cmd = hdiutil();
+        cmd.arg("convert");
+        cmd.arg(&self.image);
+        cmd.arg("-format");
+        cmd.arg(self.format.format_name());
+        if self.overwrite {
+            cmd.arg("-ov");
+        }
+        if let Some(size) = self.segment_size {
+            cmd.arg("-segmentSize");
+            cmd.arg(size.size_arg());
+        }
+        if let Some(align) = self.align {
+            cmd.arg("-align");
+            cmd.arg(align.to_string());
+        }
+        for key in &self.image_keys {
+            cmd.arg("-imagekey");
+            cmd.arg(key);
+        }
+        for key in &self.target_image_keys {
+            cmd.arg("-tgtimagekey");
+            cmd.arg(key);
+        }
+        if self.progress.is_some() {
+            cmd.arg("-puppetstrings");
+        }
+        if let Some(ref library) = self.library {
+            cmd.arg("-library");
+            cmd.arg(library);
+        }
+        cmd.args(&self.extra_args);
+        cmd.arg("-o");
+        cmd.arg(output);
+        cmd
+    }
+
+    /// Write the converted image to `output`, returning the path of the new image.
+    ///
+    /// The format's extension is appended when `output` does not already end with it, like
+    /// hdiutil itself would, so the returned path can differ from `output`.
+    pub fn convert<P: AsRef<Path>>(mut self, output: P) -> io::Result<PathBuf> {
+        let output = path_with_extension(output.as_ref(), self.format.extension());
+        let mut cmd = self.build_command(&output);
+        created_path(output_with_progress(&mut cmd, None, self.progress.as_deref_mut(), self.timeout)?, output)
+    }
+
+    /// Write the converted image to a temporary path.
+    ///
+    /// The path ends with the extension for the image's format, and the image is deleted when
+    /// the returned path is dropped.
+    pub fn convert_temp(self) -> io::Result<TempImagePath> {
+        let path = TempImagePath::with_extension(self.format.extension())?;
+        path.check_created(&self.overwrite().convert(&path)?)?;
+        Ok(path)
+    }
+}
+
+/// List every segment of a segmented image, starting with `first` itself.
+///
+/// Segments after the first are named like `Image.002.dmgpart` next to `Image.dmg`.
+pub fn segment_paths<P: AsRef<Path>>(first: P) -> io::Result<Vec<PathBuf>> {
+    let first = first.as_ref();
+    let prefix = match first.file_stem() {
+        Some(stem) => format!("{}.", stem.to_string_lossy()),
+        None => return Ok(vec![first.to_path_buf()]),
+    };
+    let directory = match first.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+
+    let mut parts = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&prefix) && name.ends_with(".dmgpart") {
+            parts.push(first.with_file_name(&*name));
+        }
+    }
+    parts.sort();
+    parts.insert(0, first.to_path_buf());
+    Ok(parts)
+}
+
+/// Split an existing image into segments of at most `size`, returning their paths.
+///
+/// The first segment is written to `first`.
+pub fn segment<P: AsRef<Path>, Q: AsRef<Path>, S: Into<Size>>(image: P, first: Q, size: S)
+        -> io::Result<Vec<PathBuf>> {
+    let mut cmd = hdiutil();
+    cmd.arg("segment");
+    cmd.arg("-o");
+    cmd.arg(first.as_ref());
+    cmd.arg("-segmentSize");
+    cmd.arg(size.into().size_arg());
+    cmd.arg(image.as_ref());
+    run(&mut cmd)?;
+    segment_paths(first)
+}
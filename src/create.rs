@@ -0,0 +1,1363 @@
+// Copyright 2017 dmg Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Creating new disk images.
+
+use std::convert::{TryFrom, TryInto};
+use std::{cmp, env, fmt, fs};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::Duration;
+
+use log::{error, info};
+use tempfile::{TempDir, TempPath};
+
+#[cfg(feature = "async")]
+use super::{output_with_input_async, replay_progress};
+use super::{check_status, detach_on_error, first_volume, global_lock, hdiutil, output_with_progress, parse_plist};
+use super::{detach_image, parse_volumes, run, wait_for_mounts, DEFAULT_MOUNT_WAIT};
+use super::convert::{Convert, ConvertFormat};
+use super::{Attach, DmgError, Verbosity, Handle, Info, Passphrase, Progress, ProgressFn, With};
+
+format_enum! {
+    /// Image formats `hdiutil create` can produce.
+    ///
+    /// See [`ConvertFormat`](../convert/enum.ConvertFormat.html) for the formats images can be
+    /// converted into.
+    pub enum FolderImageFormat {
+        /// UDIF read/write image.
+        UDRW,
+        /// UDIF read-only image.
+        UDRO,
+        /// UDIF ADC-compressed image.
+        UDCO,
+        /// UDIF zlib-compressed image.
+        UDZO,
+        /// UDIF bzip2-compressed image.
+        UDBZ,
+        /// UDIF lzfse-compressed image (macOS 10.11+ only).
+        ULFO,
+        /// UDIF lzma-compressed image (macOS 10.15+ only).
+        ULMO,
+        /// UDIF entire image with MD5 checksum.
+        UFBI,
+        /// DVD/CD-R master for export.
+        UDTO,
+        /// Sparse image, grows with content.
+        UDSP,
+        /// Sparse bundle image, grows with content.
+        UDSB,
+        /// Hybrid image (HFS+/ISO/UDF).
+        UNIV,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Bytes,
+    Sectors,
+    Mebibytes,
+    Gibibytes,
+}
+
+/// Size of a disk image, as understood by hdiutil.
+///
+/// Plain `u64`s convert into a size in bytes. Sizes compare equal when they are the same number
+/// of bytes, whatever unit they were built with.
+#[derive(Debug, Clone, Copy)]
+pub struct Size {
+    count: u64,
+    unit: Unit,
+}
+
+impl Size {
+    /// Size in bytes.
+    pub fn bytes(count: u64) -> Size {
+        Size { count, unit: Unit::Bytes }
+    }
+
+    /// Size in 512-byte sectors.
+    pub fn sectors(count: u64) -> Size {
+        Size { count, unit: Unit::Sectors }
+    }
+
+    /// Size in mebibytes (1024 * 1024 bytes).
+    pub fn mib(count: u64) -> Size {
+        Size { count, unit: Unit::Mebibytes }
+    }
+
+    /// Size in gibibytes (1024 * 1024 * 1024 bytes).
+    pub fn gib(count: u64) -> Size {
+        Size { count, unit: Unit::Gibibytes }
+    }
+
+    /// Total number of bytes.
+    ///
+    /// Fails with `DmgError::SizeOverflow` if that does not fit in a `u64`.
+    pub fn as_bytes(&self) -> Result<u64, DmgError> {
+        match self.count.checked_mul(self.unit_bytes()) {
+            Some(bytes) => Ok(bytes),
+            None => Err(DmgError::SizeOverflow(*self)),
+        }
+    }
+
+    fn unit_bytes(&self) -> u64 {
+        match self.unit {
+            Unit::Bytes => 1,
+            Unit::Sectors => 512,
+            Unit::Mebibytes => 1024 * 1024,
+            Unit::Gibibytes => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// The `-size` argument, in hdiutil's mkfile(8) style. Note `b` means sectors, not bytes.
+    pub(crate) fn size_arg(&self) -> String {
+        let suffix = match self.unit {
+            Unit::Bytes => "",
+            Unit::Sectors => "b",
+            Unit::Mebibytes => "m",
+            Unit::Gibibytes => "g",
+        };
+        format!("{}{}", self.count, suffix)
+    }
+}
+
+impl PartialEq for Size {
+    fn eq(&self, other: &Size) -> bool {
+        // Widened so sizes too large for as_bytes() still compare
+        let bytes = |size: &Size| u128::from(size.count) * u128::from(size.unit_bytes());
+        bytes(self) == bytes(other)
+    }
+}
+
+impl Eq for Size {}
+
+/// Formats the size in its own unit, like `200 MiB`.
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self.unit {
+            Unit::Bytes => "bytes",
+            Unit::Sectors => "sectors",
+            Unit::Mebibytes => "MiB",
+            Unit::Gibibytes => "GiB",
+        };
+        write!(f, "{} {}", self.count, unit)
+    }
+}
+
+impl From<u64> for Size {
+    fn from(bytes: u64) -> Size {
+        Size::bytes(bytes)
+    }
+}
+
+/// Filesystems a new image can be formatted with.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    /// Mac OS Extended.
+    HFSPlus,
+    /// Mac OS Extended, journaled.
+    HFSPlusJ,
+    /// Mac OS Extended, case-sensitive.
+    HFSPlusX,
+    /// Mac OS Extended, case-sensitive and journaled.
+    HFSPlusJX,
+    /// Apple File System (macOS 10.13+ only).
+    APFS,
+    /// Apple File System, case-sensitive (macOS 10.13+ only).
+    APFSX,
+    /// Microsoft exFAT.
+    ExFAT,
+    /// Microsoft FAT32.
+    FAT32,
+    /// Universal Disk Format.
+    UDF,
+}
+
+impl Filesystem {
+    /// Whether the filesystem supports a volume name this long. FAT32 labels are limited to 11
+    /// bytes, exFAT labels to 11 UTF-16 code units, every other filesystem to 255 of them.
+    fn fits_name(&self, name: &str) -> bool {
+        match *self {
+            Filesystem::FAT32 => name.len() <= 11,
+            Filesystem::ExFAT => name.encode_utf16().count() <= 11,
+            _ => name.encode_utf16().count() <= VolumeName::MAX_LEN,
+        }
+    }
+
+    fn fs_name(&self) -> &'static str {
+        match *self {
+            Filesystem::HFSPlus => "HFS+",
+            Filesystem::HFSPlusJ => "JHFS+",
+            Filesystem::HFSPlusX => "HFSX",
+            Filesystem::HFSPlusJX => "JHFS+X",
+            Filesystem::APFS => "APFS",
+            Filesystem::APFSX => "APFSX",
+            Filesystem::ExFAT => "ExFAT",
+            Filesystem::FAT32 => "FAT32",
+            Filesystem::UDF => "UDF",
+        }
+    }
+}
+
+/// Partition layouts for new images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// No partition map.
+    None,
+    /// Single partition, Apple Partition Map.
+    Spud,
+    /// Single partition, GUID Partition Map.
+    GptSpud,
+    /// Single partition, Master Boot Record.
+    MbrSpud,
+    /// Single partition, CD/DVD.
+    Spcd,
+    /// Multiple partitions for universal CD/DVD.
+    UniversalCd,
+    /// Multiple partitions for universal hard disks.
+    UniversalHd,
+    /// Single partition, ISO CD/DVD.
+    IsoCd,
+}
+
+impl Layout {
+    fn layout_name(&self) -> &'static str {
+        match *self {
+            Layout::None => "NONE",
+            Layout::Spud => "SPUD",
+            Layout::GptSpud => "GPTSPUD",
+            Layout::MbrSpud => "MBRSPUD",
+            Layout::Spcd => "SPCD",
+            Layout::UniversalCd => "UNIVERSAL CD",
+            Layout::UniversalHd => "UNIVERSAL HD",
+            Layout::IsoCd => "ISOCD",
+        }
+    }
+}
+
+/// Encryption algorithms for new images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// AES with a 128-bit key.
+    Aes128,
+    /// AES with a 256-bit key.
+    Aes256,
+}
+
+impl Encryption {
+    fn encryption_name(&self) -> &'static str {
+        match *self {
+            Encryption::Aes128 => "AES-128",
+            Encryption::Aes256 => "AES-256",
+        }
+    }
+}
+
+/// A volume name, checked so hdiutil does not silently truncate or reject it.
+///
+/// Names are limited to 255 UTF-16 code units, or 11 bytes on FAT32, 11 code units on exFAT and
+/// less on hybrid ISO9660 and Joliet images, and cannot contain NUL characters. The create
+/// builders' `volume_name()` accepts anything convertible into one, reporting invalid names when
+/// the image is created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeName(OsString);
+
+impl VolumeName {
+    const MAX_LEN: usize = 255;
+
+    /// Check and wrap a volume name.
+    pub fn new<S: Into<OsString>>(name: S) -> Result<VolumeName, DmgError> {
+        let name = name.into();
+        let text = name.to_string_lossy();
+        let reason = if text.is_empty() {
+            "volume name is empty"
+        } else if text.contains('\0') {
+            "volume name contains a NUL character"
+        } else if text.encode_utf16().count() > VolumeName::MAX_LEN {
+            "volume name is longer than 255 UTF-16 code units"
+        } else {
+            return Ok(VolumeName(name));
+        };
+        Err(DmgError::InvalidVolumeName { name, reason })
+    }
+
+    fn check_filesystem(&self, filesystem: Filesystem) -> Result<(), DmgError> {
+        if !filesystem.fits_name(&self.0.to_string_lossy()) {
+            return Err(DmgError::InvalidVolumeName {
+                name: self.0.clone(),
+                reason: "volume name is too long for the filesystem",
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the name fits the ISO9660 volume identifier of 32 bytes and, with `joliet`, the
+    /// Joliet one of 16 UTF-16 code units.
+    fn check_hybrid(&self, joliet: bool) -> Result<(), DmgError> {
+        let text = self.0.to_string_lossy();
+        let reason = if text.len() > 32 {
+            "volume name is longer than 32 bytes for ISO9660"
+        } else if joliet && text.encode_utf16().count() > 16 {
+            "volume name is longer than 16 UTF-16 code units for Joliet"
+        } else {
+            return Ok(());
+        };
+        Err(DmgError::InvalidVolumeName { name: self.0.clone(), reason })
+    }
+}
+
+/// Copy of the error a volume name conversion failed with, so builders can report it every time
+/// they are validated.
+fn name_error(err: &DmgError) -> DmgError {
+    match *err {
+        DmgError::InvalidVolumeName { ref name, reason } => DmgError::InvalidVolumeName { name: name.clone(), reason },
+        // Only reachable through a custom conversion, which cannot be copied
+        _ => DmgError::InvalidOptions("the volume name could not be converted"),
+    }
+}
+
+impl AsRef<OsStr> for VolumeName {
+    fn as_ref(&self) -> &OsStr {
+        &self.0
+    }
+}
+
+macro_rules! volume_name_from {
+    ($($from:ty),*) => {
+        $(impl<'a> TryFrom<$from> for VolumeName {
+            type Error = DmgError;
+            fn try_from(name: $from) -> Result<VolumeName, DmgError> {
+                VolumeName::new(name)
+            }
+        })*
+    }
+}
+
+volume_name_from!(&'a str, String, &'a OsStr, OsString);
+
+/// Whether ownership of the source files is preserved in the new image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrcOwners {
+    /// Preserve the owners of the source files.
+    On,
+    /// Ignore the owners of the source files.
+    Off,
+    /// Preserve owners only if the source volume honours them.
+    Auto,
+}
+
+impl SrcOwners {
+    fn arg(&self) -> &'static str {
+        match *self {
+            SrcOwners::On => "on",
+            SrcOwners::Off => "off",
+            SrcOwners::Auto => "auto",
+        }
+    }
+}
+
+/// The `key=value` argument for `-imagekey` and similar options.
+pub(crate) fn image_key_arg(key: &OsStr, value: &OsStr) -> OsString {
+    let mut arg = OsString::from(key);
+    arg.push("=");
+    arg.push(value);
+    arg
+}
+
+/// What to delete when a temporary image is dropped.
+#[derive(Debug)]
+enum TempGuard {
+    File(TempPath),
+    /// Sparse bundles are directories, so they live inside a temporary directory of their own.
+    Bundle(TempDir),
+}
+
+/// Path to a temporary disk image, deleted when dropped.
+///
+/// Make sure the image is detached before this is dropped, deleting an attached image can wedge
+/// its device. Either keep both in a [`TempImage`](struct.TempImage.html), or
+/// [`persist()`](#method.persist) the path and clean it up after detaching.
+///
+/// Created with `create_temp()` on any of the create builders.
+#[derive(Debug)]
+#[must_use = "dropping a `TempImagePath` deletes the image right away"]
+pub struct TempImagePath {
+    path: PathBuf,
+    guard: TempGuard,
+}
+
+impl TempImagePath {
+    /// A temporary path ending with `extension` in the system temporary directory.
+    pub(crate) fn with_extension(extension: &str) -> io::Result<TempImagePath> {
+        TempImagePath::new_in(&env::temp_dir(), &format!(".{}", extension))
+    }
+
+    pub(crate) fn new_in(dir: &Path, suffix: &str) -> io::Result<TempImagePath> {
+        if suffix.ends_with(".sparsebundle") {
+            let dir = tempfile::Builder::new().prefix("dmg").tempdir_in(dir)?;
+            return Ok(TempImagePath {
+                path: dir.path().join(format!("image{}", suffix)),
+                guard: TempGuard::Bundle(dir),
+            });
+        }
+        let file = tempfile::Builder::new().suffix(suffix).tempfile_in(dir)?.into_temp_path();
+        Ok(TempImagePath {
+            path: file.to_path_buf(),
+            guard: TempGuard::File(file),
+        })
+    }
+
+    /// Fail if hdiutil reported creating the image anywhere but this path.
+    ///
+    /// Such an image is deleted, nothing else would clean it up.
+    pub(crate) fn check_created(&self, created: &Path) -> io::Result<()> {
+        let same = match (created.canonicalize(), self.path.canonicalize()) {
+            (Ok(created), Ok(path)) => created == path,
+            _ => created == self.path,
+        };
+        if same {
+            return Ok(());
+        }
+        let _ = if created.is_dir() { fs::remove_dir_all(created) } else { fs::remove_file(created) };
+        Err(DmgError::UnexpectedOutput("created image path").into())
+    }
+
+    /// Keep the image instead of deleting it on drop, returning its path.
+    pub fn persist(self) -> io::Result<PathBuf> {
+        match self.guard {
+            TempGuard::File(file) => file.keep().map_err(|err| err.error),
+            TempGuard::Bundle(dir) => {
+                // The bundle itself lives inside the kept directory
+                let _dir = dir.keep();
+                Ok(self.path)
+            },
+        }
+    }
+}
+
+impl Deref for TempImagePath {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempImagePath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A temporary disk image that is attached, detached and then deleted when dropped.
+///
+/// Created with `create_and_attach_temp()` on any of the create builders.
+#[derive(Debug)]
+#[must_use = "dropping a `TempImage` detaches and deletes the image right away"]
+pub struct TempImage {
+    // Fields drop in order, so the image is always detached before it is deleted
+    with: With,
+    path: TempImagePath,
+}
+
+impl TempImage {
+    /// Path of the temporary image file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Access the [`Info`](../struct.Info.html) of the attached image.
+impl Deref for TempImage {
+    type Target = Info;
+    fn deref(&self) -> &Info {
+        &self.with
+    }
+}
+
+struct CommonOptions {
+    overwrite: bool,
+    volume_name: Option<Result<VolumeName, DmgError>>,
+    filesystem: Option<Filesystem>,
+    layout: Option<Layout>,
+    partition_type: Option<OsString>,
+    align: Option<u64>,
+    stretch: Option<Size>,
+    auto_stretch: bool,
+    encryption: Option<Encryption>,
+    passphrase: Option<Passphrase>,
+    progress: Option<Box<ProgressFn>>,
+    attach: bool,
+    verbosity: Verbosity,
+    timeout: Option<Duration>,
+    band_size: Option<Size>,
+    image_keys: Vec<OsString>,
+    library: Option<PathBuf>,
+    extra_args: Vec<OsString>,
+}
+
+impl CommonOptions {
+    fn new() -> CommonOptions {
+        CommonOptions {
+            overwrite: false,
+            volume_name: None,
+            filesystem: None,
+            layout: None,
+            partition_type: None,
+            align: None,
+            stretch: None,
+            auto_stretch: false,
+            encryption: None,
+            passphrase: None,
+            progress: None,
+            attach: false,
+            verbosity: Verbosity::Default,
+            timeout: None,
+            band_size: None,
+            image_keys: Vec::new(),
+            library: None,
+            extra_args: Vec::new(),
+        }
+    }
+
+    fn add_args(&self, cmd: &mut Command) {
+        if self.overwrite {
+            cmd.arg("-ov");
+        }
+        if let Some(Ok(ref name)) = self.volume_name {
+            cmd.arg("-volname");
+            cmd.arg(name);
+        }
+        if let Some(fs) = self.filesystem {
+            cmd.arg("-fs");
+            cmd.arg(fs.fs_name());
+        }
+        if let Some(layout) = self.layout {
+            cmd.arg("-layout");
+            cmd.arg(layout.layout_name());
+        }
+        if let Some(ref partition_type) = self.partition_type {
+            cmd.arg("-partitionType");
+            cmd.arg(partition_type);
+        }
+        if let Some(align) = self.align {
+            cmd.arg("-align");
+            cmd.arg(align.to_string());
+        }
+        if let Some(stretch) = self.stretch {
+            cmd.arg("-stretch");
+            cmd.arg(stretch.size_arg());
+        }
+        if self.auto_stretch {
+            cmd.arg("-autostretch");
+        }
+        if let Some(encryption) = self.encryption {
+            cmd.arg("-encryption");
+            cmd.arg(encryption.encryption_name());
+        }
+        if self.passphrase.is_some() {
+            cmd.arg("-stdinpass");
+        }
+        // Checked by validate()
+        if let Some(Ok(bytes)) = self.band_size.map(|size| size.as_bytes()) {
+            cmd.arg("-imagekey");
+            cmd.arg(format!("sparse-band-size={}", bytes / 512));
+        }
+        for key in &self.image_keys {
+            cmd.arg("-imagekey");
+            cmd.arg(key);
+        }
+        if self.progress.is_some() {
+            cmd.arg("-puppetstrings");
+        }
+        if self.attach {
+            cmd.arg("-attach");
+            cmd.arg("-plist");
+        }
+        if let Some(ref library) = self.library {
+            cmd.arg("-library");
+            cmd.arg(library);
+        }
+        // -quiet would suppress the plist attaching on create parses
+        if !(self.attach && matches!(self.verbosity, Verbosity::Quiet)) {
+            self.verbosity.add_args(cmd);
+        }
+        cmd.args(&self.extra_args);
+    }
+
+    fn input(&self) -> Option<&[u8]> {
+        self.passphrase.as_ref().map(Passphrase::as_bytes)
+    }
+
+    /// Report any invalid options before running hdiutil.
+    fn validate(&self) -> io::Result<()> {
+        if let Some(size) = self.band_size {
+            let bytes = size.as_bytes()?;
+            if bytes % 512 != 0 || !(bytes / 512).is_power_of_two() {
+                return Err(DmgError::InvalidBandSize(size).into());
+            }
+        }
+        match self.volume_name.as_ref() {
+            Some(Err(err)) => Err(name_error(err).into()),
+            Some(Ok(name)) => match self.filesystem {
+                Some(filesystem) => name.check_filesystem(filesystem).map_err(Into::into),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+macro_rules! common_options_build {
+    () => {
+        /// Overwrite an existing file at the destination path.
+        pub fn overwrite(mut self) -> Self {
+            self.common.overwrite = true;
+            self
+        }
+
+        /// Set the volume name of the new image.
+        ///
+        /// An invalid [`VolumeName`](struct.VolumeName.html) makes creating the image fail.
+        pub fn volume_name<N>(mut self, name: N) -> Self
+                where N: TryInto<VolumeName>, N::Error: Into<DmgError> {
+            self.common.volume_name = Some(name.try_into().map_err(Into::into));
+            self
+        }
+
+        /// Format the new image with the given filesystem instead of hdiutil's default.
+        pub fn filesystem(mut self, filesystem: Filesystem) -> Self {
+            self.common.filesystem = Some(filesystem);
+            self
+        }
+
+        /// Use the given partition layout instead of hdiutil's default.
+        pub fn layout(mut self, layout: Layout) -> Self {
+            self.common.layout = Some(layout);
+            self
+        }
+
+        /// Set the type of the image's data partition, such as `Apple_HFSX`.
+        pub fn partition_type<S: Into<OsString>>(mut self, partition_type: S) -> Self {
+            self.common.partition_type = Some(partition_type.into());
+            self
+        }
+
+        /// Align the data partition to `sectors` 512-byte sectors.
+        ///
+        /// The final image may be rounded up in size to honour the alignment.
+        pub fn align(mut self, sectors: u64) -> Self {
+            self.common.align = Some(sectors);
+            self
+        }
+
+        /// Let the filesystem later grow up to `size` when the image is resized.
+        pub fn stretch<S: Into<Size>>(mut self, size: S) -> Self {
+            self.common.stretch = Some(size.into());
+            self
+        }
+
+        /// Let hdiutil pick a large maximum size the filesystem can later grow to.
+        pub fn auto_stretch(mut self) -> Self {
+            self.common.auto_stretch = true;
+            self
+        }
+
+        /// Encrypt the new image.
+        ///
+        /// Without a [`passphrase()`](#method.passphrase) hdiutil will prompt for one.
+        pub fn encryption(mut self, encryption: Encryption) -> Self {
+            self.common.encryption = Some(encryption);
+            self
+        }
+
+        /// Passphrase for an encrypted image, passed to hdiutil over stdin.
+        pub fn passphrase<P: Into<Passphrase>>(mut self, passphrase: P) -> Self {
+            self.common.passphrase = Some(passphrase.into());
+            self
+        }
+
+        /// Ask hdiutil to print as little as possible.
+        ///
+        /// Ignored by [`attach_on_create()`](#method.attach_on_create), which needs hdiutil's plist.
+        pub fn quiet(mut self) -> Self {
+            self.common.verbosity = Verbosity::Quiet;
+            self
+        }
+
+        /// Ask hdiutil to print extra diagnostics, which are logged as warnings.
+        pub fn verbose(mut self) -> Self {
+            self.common.verbosity = Verbosity::Verbose;
+            self
+        }
+
+        /// Kill hdiutil and fail with `DmgError::TimedOut` if creating takes longer than `timeout`.
+        ///
+        /// Only applies to the blocking methods.
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.common.timeout = Some(timeout);
+            self
+        }
+
+        /// Set the size of the band files making up a sparse bundle.
+        ///
+        /// Larger bands mean fewer files, which helps when storing bundles on network shares. The
+        /// size has to be a power of two number of 512-byte sectors, such as `Size::mib(64)`.
+        pub fn band_size<S: Into<Size>>(mut self, size: S) -> Self {
+            self.common.band_size = Some(size.into());
+            self
+        }
+
+        key_value_fn!("Set an image key such as `diskimage-class`", "-imagekey", image_key, common.image_keys);
+        library_fn!(common.library);
+
+        extra_args_fns!(common.extra_args);
+
+        /// Call `on_progress` with progress updates while the image is created.
+        pub fn on_progress<F: FnMut(Progress) + Send + 'static>(mut self, on_progress: F) -> Self {
+            self.common.progress = Some(Box::new(on_progress));
+            self
+        }
+    }
+}
+
+// Looks for hdiutil's "created: /path/to/image.dmg" line
+pub(crate) fn parse_created(stdout: &[u8]) -> Option<PathBuf> {
+    let stdout = String::from_utf8_lossy(stdout);
+    let path = stdout.lines().find_map(|line| line.strip_prefix("created:"))?.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+/// The path of the image hdiutil created at `path`, or the error it failed with.
+pub(crate) fn created_path(output: Output, path: PathBuf) -> io::Result<PathBuf> {
+    Ok(parse_created(&check_status(output)?.stdout).unwrap_or(path))
+}
+
+/// Append `extension` to `path` unless it already ends with it.
+pub(crate) fn path_with_extension(path: &Path, extension: &str) -> PathBuf {
+    if path.extension().is_some_and(|ext| ext == extension) {
+        path.to_path_buf()
+    } else {
+        let mut path = path.as_os_str().to_os_string();
+        path.push(".");
+        path.push(extension);
+        PathBuf::from(path)
+    }
+}
+
+macro_rules! create_fns {
+    () => {
+        /// Build the hdiutil command [`create()`](#method.create) would run, without running it.
+        ///
+        /// Invalid options are reported like `create()` would report them. Useful to log or
+        /// inspect the arguments, or to run hdiutil yourself.
+        pub fn command<P: AsRef<Path>>(&self, path: P) -> io::Result<Command> {
+            self.validate()?;
+            self.build_command(&path_with_extension(path.as_ref(), self.extension()))
+        }
+
+        /// Create the disk image at `path`, returning the path of the new image.
+        ///
+        /// The [`extension()`](#method.extension) is appended when `path` does not already end
+        /// with it, like hdiutil itself would, so the returned path can differ from `path`.
+        pub fn create<P: AsRef<Path>>(mut self, path: P) -> io::Result<PathBuf> {
+            self.validate()?;
+            let path = path_with_extension(path.as_ref(), self.extension());
+            let mut cmd = self.build_command(&path)?;
+            let mut progress = self.common.progress.take();
+            let output = output_with_progress(&mut cmd, self.common.input(), progress.as_deref_mut(), self.common.timeout)?;
+            created_path(output, path)
+        }
+
+        /// Create the disk image at `path` without blocking the current thread.
+        ///
+        /// Asynchronous version of [`create()`](#method.create), progress updates are only
+        /// delivered once hdiutil finishes.
+        #[cfg(feature = "async")]
+        pub async fn create_async<P: AsRef<Path>>(mut self, path: P) -> io::Result<PathBuf> {
+            self.validate()?;
+            let path = path_with_extension(path.as_ref(), self.extension());
+            let output = output_with_input_async(self.build_command(&path)?, self.common.input()).await?;
+            replay_progress(&output.stdout, self.common.progress.as_deref_mut());
+            created_path(output, path)
+        }
+
+        /// Create the disk image at `path` and attach it in the same hdiutil call, returning the
+        /// handle and the path of the new image.
+        ///
+        /// The [`extension()`](#method.extension) is appended to `path` like
+        /// [`create()`](#method.create) does. Waits for the mount point like
+        /// [`Attach::mount_wait()`](../struct.Attach.html#method.mount_wait) does by default,
+        /// detaching the image again if it does not show up or hdiutil's output cannot be parsed.
+        pub fn attach_on_create<P: AsRef<Path>>(mut self, path: P) -> io::Result<(Handle, PathBuf)> {
+            self.validate()?;
+            self.common.attach = true;
+            let path = path_with_extension(path.as_ref(), self.extension());
+            let mut cmd = self.build_command(&path)?;
+            let mut progress = self.common.progress.take();
+            let output = {
+                // Released before a failed check detaches the image, which takes the lock too
+                let _locked = global_lock();
+                output_with_progress(&mut cmd, self.common.input(), progress.as_deref_mut(), self.common.timeout)?
+            };
+            let output = check_status(output)?;
+            let volumes = parse_plist(output.stdout).and_then(|plist| parse_volumes(&plist))
+                .inspect_err(|err| detach_unparsed(&path, err))?;
+            let info = detach_on_error(volumes, |volumes| first_volume(wait_for_mounts(volumes, DEFAULT_MOUNT_WAIT)?))?;
+            Ok((Handle(info), path))
+        }
+
+        /// Create the disk image at a temporary path.
+        ///
+        /// The path ends with the extension for the image's format, and the image is deleted
+        /// when the returned path is dropped.
+        pub fn create_temp(self) -> io::Result<TempImagePath> {
+            let path = TempImagePath::with_extension(self.extension())?;
+            path.check_created(&self.overwrite().create(&path)?)?;
+            Ok(path)
+        }
+
+        /// Create the disk image at a temporary path and attach it.
+        ///
+        /// `options` configures how the image is attached, for example `|attach| attach.hidden()`.
+        pub fn create_and_attach_temp<F: FnOnce(Attach) -> Attach>(self, options: F) -> io::Result<TempImage> {
+            let path = self.create_temp()?;
+            let with = options(Attach::new(&*path)).with()?;
+            Ok(TempImage { with, path })
+        }
+
+        /// Create the disk image at a temporary path in `dir` ending with `suffix`.
+        ///
+        /// The suffix has to end with the [`extension()`](#method.extension), such as
+        /// `-scratch.sparseimage`, otherwise this fails with `DmgError::InvalidOptions`. A
+        /// sparse bundle is created inside a temporary directory, since bundles are directories
+        /// themselves.
+        pub fn create_temp_in<P: AsRef<Path>>(self, dir: P, suffix: &str) -> io::Result<TempImagePath> {
+            if !suffix.ends_with(&format!(".{}", self.extension())) {
+                return Err(DmgError::InvalidOptions("the suffix does not end with the format's extension").into());
+            }
+            let path = TempImagePath::new_in(dir.as_ref(), suffix)?;
+            path.check_created(&self.overwrite().create(&path)?)?;
+            Ok(path)
+        }
+    }
+}
+
+/// Force detach the image hdiutil created and attached at `path` but whose devices could not be
+/// parsed from its output, so it is not left attached without a handle.
+fn detach_unparsed(path: &Path, err: &io::Error) {
+    info!("Could not parse the attached image, detaching: {}", err);
+    if let Err(err) = detach_image(path, true) {
+        error!("Could not detach {:?}: {}", path, err);
+    }
+}
+
+/// Space taken up by a file on a typical filesystem, rounded up to whole allocation blocks.
+const BLOCK_SIZE: u64 = 4096;
+
+/// Estimate how many bytes copying `folder` onto a fresh volume takes, without following
+/// symlinks.
+pub(crate) fn folder_size(folder: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    let mut pending = vec![folder.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        // Every directory takes up at least a block for its entries
+        total += BLOCK_SIZE;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.path().symlink_metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                total += cmp::max(metadata.len().div_ceil(BLOCK_SIZE), 1) * BLOCK_SIZE;
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Builder to create a disk image from the contents of a folder.
+#[must_use = "builders do nothing unless `create()` or similar is called"]
+pub struct FromFolder {
+    folder: PathBuf,
+    format: FolderImageFormat,
+    segment_size: Option<Size>,
+    source_owners: Option<SrcOwners>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>,
+    cross_devices: Option<bool>,
+    scrub: bool,
+    size: FolderSize,
+    common: CommonOptions,
+}
+
+enum FolderSize {
+    Default,
+    Fit(Size),
+}
+
+impl FromFolder {
+    /// Creates a new builder imaging `folder` using the given format.
+    pub fn new<P: Into<PathBuf>>(folder: P, format: FolderImageFormat) -> FromFolder {
+        FromFolder {
+            folder: folder.into(),
+            format,
+            segment_size: None,
+            source_owners: None,
+            uid: None,
+            gid: None,
+            mode: None,
+            cross_devices: None,
+            scrub: false,
+            size: FolderSize::Default,
+            common: CommonOptions::new(),
+        }
+    }
+
+    /// Set the owner of the root folder of the new volume.
+    pub fn uid(mut self, uid: u32) -> FromFolder {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Set the group of the root folder of the new volume.
+    pub fn gid(mut self, gid: u32) -> FromFolder {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Set the permissions of the root folder of the new volume, such as `0o755`.
+    pub fn mode(mut self, mode: u32) -> FromFolder {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Whether to copy the contents of other filesystems mounted inside the folder.
+    ///
+    /// When not set hdiutil's default is used.
+    pub fn cross_devices(mut self, enabled: bool) -> FromFolder {
+        self.cross_devices = Some(enabled);
+        self
+    }
+
+    /// Skip temporary files, such as swap and cache files, when imaging the folder.
+    ///
+    /// hdiutil decides what counts as temporary. Finder metadata like `.DS_Store` is not
+    /// temporary, so remove it from the folder first if it should be left out.
+    pub fn scrub(mut self) -> FromFolder {
+        self.scrub = true;
+        self
+    }
+
+    /// Size the image to fit the folder's contents plus `slack`, instead of letting hdiutil pick.
+    ///
+    /// Useful for read/write formats like `UDRW`, which otherwise leave little free space. The
+    /// folder is measured when the image is created: symlinks count as links rather than what
+    /// they point to, and sparse files count at their full length since not every filesystem
+    /// keeps them sparse. Filesystem overhead is only roughly accounted for, so leave some slack.
+    pub fn size_to_fit<S: Into<Size>>(mut self, slack: S) -> FromFolder {
+        self.size = FolderSize::Fit(slack.into());
+        self
+    }
+
+    /// Control whether ownership of the files in the folder is preserved.
+    pub fn source_owners(mut self, source_owners: SrcOwners) -> FromFolder {
+        self.source_owners = Some(source_owners);
+        self
+    }
+
+    /// Split the image into `.dmgpart` segments of at most `size`.
+    ///
+    /// See [`convert::segment_paths()`](../convert/fn.segment_paths.html) to find them.
+    pub fn segment_size<S: Into<Size>>(mut self, size: S) -> FromFolder {
+        self.segment_size = Some(size.into());
+        self
+    }
+
+    common_options_build!();
+    create_fns!();
+
+    /// File extension of the image this builder creates, without the dot.
+    pub fn extension(&self) -> &'static str {
+        self.format.extension()
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        self.common.validate()
+    }
+
+    fn build_command(&self, path: &Path) -> io::Result<Command> {
+        // Measured on every call, so the size follows the folder's current contents
+        let size = match self.size {
+            FolderSize::Default => None,
+            FolderSize::Fit(slack) => {
+                let bytes = folder_size(&self.folder)?.checked_add(slack.as_bytes()?);
+                Some(Size::from(bytes.ok_or(DmgError::SizeOverflow(slack))?))
+            },
+        };
+        let mut cmd = hdiutil();
+        cmd.arg("create");
+        cmd.arg("-srcfolder");
+        cmd.arg(&self.folder);
+        cmd.arg("-format");
+        cmd.arg(self.format.format_name());
+        if let Some(size) = size {
+            cmd.arg("-size");
+            cmd.arg(size.size_arg());
+        }
+        if let Some(size) = self.segment_size {
+            cmd.arg("-segmentSize");
+            cmd.arg(size.size_arg());
+        }
+        if let Some(source_owners) = self.source_owners {
+            cmd.arg("-srcowners");
+            cmd.arg(source_owners.arg());
+        }
+        if let Some(uid) = self.uid {
+            cmd.arg("-uid");
+            cmd.arg(uid.to_string());
+        }
+        if let Some(gid) = self.gid {
+            cmd.arg("-gid");
+            cmd.arg(gid.to_string());
+        }
+        if let Some(mode) = self.mode {
+            cmd.arg("-mode");
+            cmd.arg(format!("{:o}", mode));
+        }
+        if let Some(cross_devices) = self.cross_devices {
+            cmd.arg(if cross_devices { "-crossdev" } else { "-nocrossdev" });
+        }
+        if self.scrub {
+            cmd.arg("-scrub");
+        }
+        self.common.add_args(&mut cmd);
+        cmd.arg(path);
+        Ok(cmd)
+    }
+}
+
+/// Builder to create a disk image from the contents of a device, such as `/dev/disk4`.
+///
+/// The device should be unmounted, or at least not written to, while it is imaged.
+#[must_use = "builders do nothing unless `create()` or similar is called"]
+pub struct FromDevice {
+    device: PathBuf,
+    format: FolderImageFormat,
+    source_owners: Option<SrcOwners>,
+    common: CommonOptions,
+}
+
+impl FromDevice {
+    /// Creates a new builder imaging `device` using the given format.
+    pub fn new<P: Into<PathBuf>>(device: P, format: FolderImageFormat) -> FromDevice {
+        FromDevice {
+            device: device.into(),
+            format,
+            source_owners: None,
+            common: CommonOptions::new(),
+        }
+    }
+
+    /// Control whether ownership of the files on the device is preserved.
+    pub fn source_owners(mut self, source_owners: SrcOwners) -> FromDevice {
+        self.source_owners = Some(source_owners);
+        self
+    }
+
+    common_options_build!();
+    create_fns!();
+
+    /// File extension of the image this builder creates, without the dot.
+    pub fn extension(&self) -> &'static str {
+        self.format.extension()
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        self.common.validate()
+    }
+
+    fn build_command(&self, path: &Path) -> io::Result<Command> {
+        let mut cmd = hdiutil();
+        cmd.arg("create");
+        cmd.arg("-srcdevice");
+        cmd.arg(&self.device);
+        cmd.arg("-format");
+        cmd.arg(self.format.format_name());
+        if let Some(source_owners) = self.source_owners {
+            cmd.arg("-srcowners");
+            cmd.arg(source_owners.arg());
+        }
+        self.common.add_args(&mut cmd);
+        cmd.arg(path);
+        Ok(cmd)
+    }
+}
+
+/// Builder to create an empty disk image of a fixed size.
+#[must_use = "builders do nothing unless `create()` or similar is called"]
+pub struct BlankImage {
+    size: Size,
+    kind: BlankKind,
+    common: CommonOptions,
+}
+
+/// Blank images are picked either by `-format` or by `-type`, hdiutil does not combine them.
+enum BlankKind {
+    Format(FolderImageFormat),
+    Type(ImageType),
+}
+
+/// Kinds of blank image `hdiutil create -type` can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageType {
+    /// A regular UDIF image of a fixed size.
+    Udif,
+    /// A sparse image, which grows with its content.
+    Sparse,
+    /// A sparse bundle, a directory of bands which grows with its content.
+    SparseBundle,
+}
+
+impl ImageType {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            ImageType::Udif => "UDIF",
+            ImageType::Sparse => "SPARSE",
+            ImageType::SparseBundle => "SPARSEBUNDLE",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            ImageType::Udif => "dmg",
+            ImageType::Sparse => "sparseimage",
+            ImageType::SparseBundle => "sparsebundle",
+        }
+    }
+}
+
+impl BlankImage {
+    /// Creates a new builder for a blank read/write image of the given size.
+    pub fn new<S: Into<Size>>(size: S) -> BlankImage {
+        BlankImage {
+            size: size.into(),
+            kind: BlankKind::Format(FolderImageFormat::UDRW),
+            common: CommonOptions::new(),
+        }
+    }
+
+    /// Use a different image format instead of `UDRW`.
+    ///
+    /// Replaces any [`image_type()`](#method.image_type) set before.
+    pub fn format(mut self, format: FolderImageFormat) -> BlankImage {
+        self.kind = BlankKind::Format(format);
+        self
+    }
+
+    /// Pick the kind of image with `-type` instead of a `-format`.
+    ///
+    /// Replaces any [`format()`](#method.format) set before, `ImageType::Sparse` is equivalent to
+    /// the `UDSP` format.
+    pub fn image_type(mut self, image_type: ImageType) -> BlankImage {
+        self.kind = BlankKind::Type(image_type);
+        self
+    }
+
+    common_options_build!();
+    create_fns!();
+
+    /// File extension of the image this builder creates, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self.kind {
+            BlankKind::Format(ref format) => format.extension(),
+            BlankKind::Type(ref image_type) => image_type.extension(),
+        }
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        self.common.validate()
+    }
+
+    fn build_command(&self, path: &Path) -> io::Result<Command> {
+        let mut cmd = hdiutil();
+        cmd.arg("create");
+        cmd.arg("-size");
+        cmd.arg(self.size.size_arg());
+        match self.kind {
+            BlankKind::Format(ref format) => {
+                cmd.arg("-format");
+                cmd.arg(format.format_name());
+            },
+            BlankKind::Type(ref image_type) => {
+                cmd.arg("-type");
+                cmd.arg(image_type.type_name());
+            },
+        }
+        self.common.add_args(&mut cmd);
+        cmd.arg(path);
+        Ok(cmd)
+    }
+}
+
+/// Builder to author a hybrid HFS+/ISO9660/Joliet/UDF image from a folder.
+///
+/// Wraps `hdiutil makehybrid`, without any filesystem flags hdiutil picks all it supports.
+#[must_use = "builders do nothing unless `create()` is called"]
+pub struct MakeHybrid {
+    folder: PathBuf,
+    hfs: bool,
+    iso: bool,
+    joliet: bool,
+    udf: bool,
+    volume_name: Option<Result<VolumeName, DmgError>>,
+    overwrite: bool,
+    extra_args: Vec<OsString>,
+}
+
+macro_rules! hybrid_fn {
+    ($doc:expr, $name:ident) => {
+        #[doc=$doc]
+        pub fn $name(mut self) -> MakeHybrid {
+            self.$name = true;
+            self
+        }
+    }
+}
+
+impl MakeHybrid {
+    /// Creates a new builder imaging `folder`.
+    pub fn new<P: Into<PathBuf>>(folder: P) -> MakeHybrid {
+        MakeHybrid {
+            folder: folder.into(),
+            hfs: false,
+            iso: false,
+            joliet: false,
+            udf: false,
+            volume_name: None,
+            overwrite: false,
+            extra_args: Vec::new(),
+        }
+    }
+
+    hybrid_fn!("Include an HFS+ filesystem.", hfs);
+    hybrid_fn!("Include an ISO9660 filesystem.", iso);
+    hybrid_fn!("Include Joliet extensions to the ISO9660 filesystem.", joliet);
+    hybrid_fn!("Include a UDF filesystem.", udf);
+    hybrid_fn!("Overwrite an existing file at the destination path.", overwrite);
+    extra_args_fns!(extra_args);
+
+    /// Set the volume name used by every filesystem.
+    ///
+    /// An invalid [`VolumeName`](struct.VolumeName.html) makes creating the image fail, as does
+    /// one longer than 32 bytes with ISO9660 or 16 UTF-16 code units with Joliet.
+    pub fn volume_name<N>(mut self, name: N) -> MakeHybrid
+            where N: TryInto<VolumeName>, N::Error: Into<DmgError> {
+        self.volume_name = Some(name.try_into().map_err(Into::into));
+        self
+    }
+
+    /// Report an invalid volume name before running hdiutil, which would truncate it.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        match self.volume_name.as_ref() {
+            Some(Err(err)) => Err(name_error(err).into()),
+            Some(Ok(name)) => {
+                // Without any flags hdiutil includes every filesystem, Joliet needs ISO9660 too
+                let all = !(self.hfs || self.iso || self.joliet || self.udf);
+                if all || self.iso || self.joliet {
+                    name.check_hybrid(all || self.joliet)?;
+                }
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn build_command(&self, path: &Path) -> Command {
+        let mut cmd = hdiutil();
+        cmd.arg("makehybrid");
+        for &(enabled, flag) in &[(self.hfs, "-hfs"), (self.iso, "-iso"), (self.joliet, "-joliet"), (self.udf, "-udf")] {
+            if enabled {
+                cmd.arg(flag);
+            }
+        }
+        if let Some(Ok(ref name)) = self.volume_name {
+            cmd.arg("-default-volume-name");
+            cmd.arg(name);
+        }
+        if self.overwrite {
+            cmd.arg("-ov");
+        }
+        cmd.args(&self.extra_args);
+        cmd.arg("-o");
+        cmd.arg(path);
+        cmd.arg(&self.folder);
+        cmd
+    }
+
+    /// Create the hybrid image at `path`.
+    pub fn create<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        self.validate()?;
+        run(&mut self.build_command(path.as_ref())).map(|_| ())
+    }
+}
+
+/// Create an image at `path` by filling a blank read/write image of `size`, optionally
+/// converting it into `format` afterwards.
+///
+/// The blank image is attached read/write and hidden, `fill` is called with its mount point to
+/// copy files in, then the image is detached. Without a `format` the `UDRW` image is kept at
+/// `path`, otherwise it is built in the temporary directory and converted to `path`, with the
+/// format's extension appended as needed. If anything fails the image is detached and deleted.
+///
+/// ```rust,no_run
+/// use std::fs;
+/// use dmg::convert::ConvertFormat;
+/// use dmg::create::{self, Size};
+///
+/// let image = create::create_image_with("App", Size::mib(50), Some(ConvertFormat::UDZO), |root| {
+///     fs::copy("target/release/app", root.join("app")).map(|_| ())
+/// }).expect("could not create");
+/// println!("Created {:?}", image);
+/// ```
+pub fn create_image_with<P, S, F>(path: P, size: S, format: Option<ConvertFormat>, fill: F)
+        -> io::Result<PathBuf> where P: AsRef<Path>, S: Into<Size>, F: FnOnce(&Path) -> io::Result<()> {
+    let blank = BlankImage::new(size);
+    match format {
+        None => {
+            let image = blank.create(path)?;
+            let result = fill_image(&image, fill);
+            if result.is_err() {
+                // Already detached, hdiutil is done with the half filled image
+                let _ = fs::remove_file(&image);
+            }
+            result.map(|_| image)
+        },
+        Some(format) => {
+            let image = blank.create_temp()?;
+            fill_image(&image, fill)?;
+            Convert::new(&*image, format).convert(path)
+        },
+    }
+}
+
+/// Attach `image` read/write, call `fill` with its mount point and detach it again.
+fn fill_image<F: FnOnce(&Path) -> io::Result<()>>(image: &Path, fill: F) -> io::Result<()> {
+    let with = Attach::new(image).mount_temp().hidden().read_write().with()?;
+    // An error drops 'with', detaching the image
+    fill(&with.mount_point)?;
+    with.into_handle().detach_retry(3, Duration::from_secs(1))
+}
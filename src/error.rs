@@ -0,0 +1,211 @@
+// Copyright 2017 dmg Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::convert::Infallible;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use super::create::Size;
+use super::image::VerifyFailure;
+
+/// Reasons an hdiutil operation can fail.
+///
+/// Every `io::Error` returned by this crate that did not come from the filesystem wraps one of
+/// these, use [`from_io_error()`](#method.from_io_error) to get it back:
+///
+/// ```rust,no_run
+/// use dmg::{Attach, DmgError};
+///
+/// match Attach::new("Test.dmg").passphrase("hunter2").attach() {
+///     Ok(handle) => println!("Mounted at {:?}", handle.mount_point),
+///     Err(ref err) if matches!(DmgError::from_io_error(err), Some(DmgError::IncorrectPassphrase)) => {
+///         println!("Try again");
+///     },
+///     Err(err) => panic!("could not attach: {}", err),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum DmgError {
+    /// hdiutil exited with a non-zero status.
+    CommandFailed {
+        /// Exit status of hdiutil.
+        status: ExitStatus,
+        /// Everything hdiutil wrote to stderr.
+        stderr: String,
+    },
+    /// hdiutil could not verify an image.
+    VerifyFailed {
+        /// Exit status of hdiutil.
+        status: ExitStatus,
+        /// Best effort classification of the failure.
+        failure: VerifyFailure,
+        /// Everything hdiutil wrote to stderr.
+        stderr: String,
+    },
+    /// The image being detached was not attached, for example because it was detached already.
+    NotAttached(PathBuf),
+    /// A mountable volume on this device was not mounted.
+    NotMounted(PathBuf),
+    /// A volume name was rejected before running hdiutil.
+    InvalidVolumeName {
+        /// The rejected name.
+        name: OsString,
+        /// Why the name was rejected.
+        reason: &'static str,
+    },
+    /// A sparse bundle band size was not a power of two number of sectors.
+    InvalidBandSize(Size),
+    /// Builder options were set that cannot be used together.
+    InvalidOptions(&'static str),
+    /// A size was too large to count in bytes.
+    SizeOverflow(Size),
+    /// A string did not name a known image format.
+    UnknownFormat(String),
+    /// hdiutil rejected the passphrase of an encrypted image.
+    IncorrectPassphrase,
+    /// hdiutil output was not a valid plist.
+    PlistParse(plist::Error),
+    /// A property was missing from hdiutil's plist output.
+    MissingProperty(&'static str),
+    /// hdiutil text output was not in the expected format.
+    UnexpectedOutput(&'static str),
+    /// hdiutil did not finish within the timeout and was killed.
+    TimedOut(Duration),
+    /// hdiutil could not be found, most likely because this is not macOS.
+    ///
+    /// Holds the path `DMG_HDIUTIL` overrode hdiutil with, if it was set, otherwise hdiutil was
+    /// looked up on `PATH`.
+    HdiutilNotFound(Option<PathBuf>),
+    /// Disk images are not supported on this platform, only on macOS.
+    ///
+    /// Not returned when `DMG_HDIUTIL` points at a replacement binary.
+    Unsupported,
+    /// hdiutil could not be run, or communicating with it failed.
+    Spawn(io::Error),
+}
+
+impl DmgError {
+    /// Get the `DmgError` wrapped by an `io::Error` returned from this crate.
+    pub fn from_io_error(err: &io::Error) -> Option<&DmgError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    /// Exit code of hdiutil, if it ran and exited with one.
+    ///
+    /// hdiutil describes failures, including those of `create`, in human readable text on stderr.
+    /// The exit code is the part of a failure meant to be checked programmatically.
+    pub fn exit_code(&self) -> Option<i32> {
+        match *self {
+            DmgError::CommandFailed { status, .. } | DmgError::VerifyFailed { status, .. } => status.code(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DmgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DmgError::CommandFailed { status, ref stderr } if stderr.trim().is_empty() => {
+                write!(f, "hdiutil failed ({})", Status(status))
+            },
+            DmgError::CommandFailed { status, ref stderr } => {
+                write!(f, "hdiutil failed ({}): {}", Status(status), stderr.trim())
+            },
+            DmgError::VerifyFailed { status, failure, ref stderr } => {
+                write!(f, "image failed to verify ({:?}, {}): {}", failure, Status(status), stderr.trim())
+            },
+            DmgError::NotAttached(ref path) => write!(f, "{:?} is not attached", path),
+            DmgError::NotMounted(ref device) => write!(f, "volume on {:?} was not mounted", device),
+            DmgError::InvalidVolumeName { ref name, reason } => write!(f, "{}: {:?}", reason, name),
+            DmgError::InvalidBandSize(size) => {
+                write!(f, "band size of {} is not a power of two number of sectors", size)
+            },
+            DmgError::SizeOverflow(size) => write!(f, "size of {} is too large", size),
+            DmgError::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            DmgError::UnknownFormat(ref name) => write!(f, "unknown image format {:?}", name),
+            DmgError::IncorrectPassphrase => write!(f, "incorrect passphrase"),
+            DmgError::PlistParse(ref err) => write!(f, "could not parse plist: {}", err),
+            DmgError::MissingProperty(name) => write!(f, "could not find property {:?}", name),
+            DmgError::UnexpectedOutput(what) => write!(f, "could not parse {}", what),
+            DmgError::TimedOut(timeout) => write!(f, "hdiutil did not finish within {:?}", timeout),
+            DmgError::HdiutilNotFound(None) => {
+                write!(f, "could not find hdiutil (disk images need macOS; set DMG_HDIUTIL to override)")
+            },
+            DmgError::HdiutilNotFound(Some(ref path)) => {
+                write!(f, "could not find hdiutil at {:?}, the path DMG_HDIUTIL is set to", path)
+            },
+            DmgError::Unsupported => write!(f, "disk images are only supported on macOS"),
+            DmgError::Spawn(ref err) => write!(f, "could not run hdiutil: {}", err),
+        }
+    }
+}
+
+/// Describes how hdiutil exited, like "exit code 1".
+struct Status(ExitStatus);
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(code) = self.0.code() {
+            return write!(f, "exit code {}", code);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = self.0.signal() {
+                return write!(f, "killed by signal {}", signal);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for DmgError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            DmgError::PlistParse(ref err) => Some(err),
+            DmgError::Spawn(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DmgError> for io::Error {
+    fn from(err: DmgError) -> io::Error {
+        let kind = match err {
+            DmgError::CommandFailed { .. } | DmgError::NotMounted(_) => ErrorKind::Other,
+            DmgError::VerifyFailed { failure: VerifyFailure::ChecksumMismatch, .. } => ErrorKind::InvalidData,
+            DmgError::VerifyFailed { failure: VerifyFailure::Format, .. } => ErrorKind::InvalidData,
+            DmgError::VerifyFailed { .. } => ErrorKind::Other,
+            DmgError::InvalidVolumeName { .. } | DmgError::InvalidBandSize(_) | DmgError::InvalidOptions(_)
+                    | DmgError::SizeOverflow(_) | DmgError::UnknownFormat(_) => {
+                ErrorKind::InvalidInput
+            },
+            DmgError::IncorrectPassphrase => ErrorKind::PermissionDenied,
+            DmgError::PlistParse(_) | DmgError::MissingProperty(_) | DmgError::UnexpectedOutput(_) => {
+                ErrorKind::InvalidData
+            },
+            DmgError::TimedOut(_) => ErrorKind::TimedOut,
+            DmgError::HdiutilNotFound(_) | DmgError::NotAttached(_) => ErrorKind::NotFound,
+            DmgError::Unsupported => ErrorKind::Unsupported,
+            DmgError::Spawn(ref err) => err.kind(),
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Lets infallible conversions, like passing a `VolumeName` itself, be used where a `DmgError`
+/// conversion is expected.
+impl From<Infallible> for DmgError {
+    fn from(never: Infallible) -> DmgError {
+        match never {}
+    }
+}
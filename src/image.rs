@@ -0,0 +1,773 @@
+// Copyright 2017 dmg Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Inspecting and maintaining existing disk images.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use plist::Value;
+
+use super::create::Size;
+use super::{check_passphrase, check_status, hdiutil, output_with_input, output_with_progress, run, run_plist};
+use super::{DmgError, Passphrase, Verbosity};
+
+/// Sector counts an image can be resized between, from [`resize_limits()`](fn.resize_limits.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResizeLimits {
+    /// Smallest size the image can be shrunk to.
+    pub min_sectors: u64,
+
+    /// Current size of the image.
+    pub current_sectors: u64,
+
+    /// Largest size the image can be grown to.
+    pub max_sectors: u64,
+}
+
+/// Builder to resize a read/write or sparse disk image.
+#[must_use = "builders do nothing unless `resize()` is called"]
+pub struct Resize {
+    image: PathBuf,
+    target: ResizeTarget,
+    grow_only: bool,
+    shrink_only: bool,
+    timeout: Option<Duration>,
+}
+
+enum ResizeTarget {
+    Size(Size),
+    Min,
+    Max,
+}
+
+impl Resize {
+    /// Creates a new builder resizing `image` to `size`.
+    ///
+    /// Use [`Size::sectors()`](../create/struct.Size.html#method.sectors) for an exact sector count.
+    pub fn new<P: Into<PathBuf>, S: Into<Size>>(image: P, size: S) -> Resize {
+        Resize::to(image.into(), ResizeTarget::Size(size.into()))
+    }
+
+    /// Creates a new builder shrinking `image` to the smallest size its contents allow.
+    ///
+    /// This is the `min_sectors` of [`resize_limits()`](fn.resize_limits.html).
+    pub fn min<P: Into<PathBuf>>(image: P) -> Resize {
+        Resize::to(image.into(), ResizeTarget::Min)
+    }
+
+    /// Creates a new builder growing `image` to the largest size it supports.
+    ///
+    /// This is the `max_sectors` of [`resize_limits()`](fn.resize_limits.html), looked up when
+    /// resizing.
+    pub fn max<P: Into<PathBuf>>(image: P) -> Resize {
+        Resize::to(image.into(), ResizeTarget::Max)
+    }
+
+    fn to(image: PathBuf, target: ResizeTarget) -> Resize {
+        Resize {
+            image,
+            target,
+            grow_only: false,
+            shrink_only: false,
+            timeout: None,
+        }
+    }
+
+    /// Only resize if the image would grow.
+    ///
+    /// Cannot be combined with [`shrink_only()`](#method.shrink_only).
+    pub fn grow_only(mut self) -> Resize {
+        self.grow_only = true;
+        self
+    }
+
+    /// Only resize if the image would shrink.
+    ///
+    /// Cannot be combined with [`grow_only()`](#method.grow_only).
+    pub fn shrink_only(mut self) -> Resize {
+        self.shrink_only = true;
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if resizing takes longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Resize {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Report any invalid options before running hdiutil.
+    fn validate(&self) -> io::Result<()> {
+        if self.grow_only && self.shrink_only {
+            return Err(DmgError::InvalidOptions("an image cannot be resized to only grow and only shrink").into());
+        }
+        Ok(())
+    }
+
+    /// The resize command, `max_sectors` is only used by a [`max()`](#method.max) target.
+    pub(crate) fn build_command(&self, max_sectors: Option<u64>) -> Command {
+        let mut cmd = hdiutil();
+        cmd.arg("resize");
+        match self.target {
+            ResizeTarget::Size(size) => {
+                cmd.arg("-size");
+                cmd.arg(size.size_arg());
+            },
+            ResizeTarget::Min => {
+                cmd.args(["-sectors", "min"]);
+            },
+            ResizeTarget::Max => {
+                // hdiutil only knows the min keyword
+                cmd.arg("-sectors");
+                cmd.arg(max_sectors.expect("max sectors not looked up").to_string());
+            },
+        }
+        if self.grow_only {
+            cmd.arg("-growonly");
+        }
+        if self.shrink_only {
+            cmd.arg("-shrinkonly");
+        }
+        cmd.arg(&self.image);
+        cmd
+    }
+
+    /// Resize the image.
+    pub fn resize(self) -> io::Result<()> {
+        self.validate()?;
+        let max_sectors = match self.target {
+            ResizeTarget::Max => Some(resize_limits(&self.image)?.max_sectors),
+            _ => None,
+        };
+        check_status(output_with_progress(&mut self.build_command(max_sectors), None, None, self.timeout)?).map(|_| ())
+    }
+}
+
+/// Resize an image to `size`.
+///
+/// Shorthand for `Resize::new(image, size).resize()`.
+pub fn resize<P: Into<PathBuf>, S: Into<Size>>(image: P, size: S) -> io::Result<()> {
+    Resize::new(image, size).resize()
+}
+
+pub(crate) fn parse_limits(stdout: &str) -> Option<ResizeLimits> {
+    let line = stdout.lines().rev().find(|line| !line.trim().is_empty())?;
+    let mut sectors = line.split_whitespace().map(|n| n.parse::<u64>());
+    let limits = ResizeLimits {
+        min_sectors: sectors.next()?.ok()?,
+        current_sectors: sectors.next()?.ok()?,
+        max_sectors: sectors.next()?.ok()?,
+    };
+    Some(limits)
+}
+
+/// Query the sizes an image can be resized between.
+pub fn resize_limits<P: AsRef<Path>>(image: P) -> io::Result<ResizeLimits> {
+    let mut cmd = hdiutil();
+    cmd.arg("resize");
+    cmd.arg("-limits");
+    cmd.arg(image.as_ref());
+
+    let output = run(&mut cmd)?;
+    match parse_limits(&String::from_utf8_lossy(&output.stdout)) {
+        Some(limits) => Ok(limits),
+        None => Err(DmgError::UnexpectedOutput("resize limits").into()),
+    }
+}
+
+/// Shrink an image to the smallest size hdiutil allows, returning its new size in sectors.
+///
+/// Useful to reclaim space after deleting files from a read/write image. The image is never
+/// grown, and left alone if it is already as small as it can be.
+pub fn resize_to_min<P: AsRef<Path>>(image: P) -> io::Result<u64> {
+    let image = image.as_ref();
+    let limits = resize_limits(image)?;
+    if limits.min_sectors >= limits.current_sectors {
+        return Ok(limits.current_sectors);
+    }
+    Resize::new(image, Size::sectors(limits.min_sectors)).shrink_only().resize()?;
+    Ok(limits.min_sectors)
+}
+
+/// Builder to compact a sparse or sparse bundle image, reclaiming unused space.
+#[must_use = "builders do nothing unless `compact()` is called"]
+pub struct Compact {
+    image: PathBuf,
+    battery_allowed: bool,
+    timeout: Option<Duration>,
+}
+
+impl Compact {
+    /// Creates a new builder compacting `image`.
+    pub fn new<P: Into<PathBuf>>(image: P) -> Compact {
+        Compact {
+            image: image.into(),
+            battery_allowed: false,
+            timeout: None,
+        }
+    }
+
+    /// Allow compacting while running on battery power.
+    pub fn battery_allowed(mut self) -> Compact {
+        self.battery_allowed = true;
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if compacting takes longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Compact {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn build_command(&self) -> Command {
+        let mut cmd = hdiutil();
+        cmd.arg("compact");
+        if self.battery_allowed {
+            cmd.arg("-batteryallowed");
+        }
+        cmd.arg(&self.image);
+        cmd
+    }
+
+    /// Compact the image, returning the number of bytes reclaimed if hdiutil reported it.
+    pub fn compact(self) -> io::Result<Option<u64>> {
+        let output = check_status(output_with_progress(&mut self.build_command(), None, None, self.timeout)?)?;
+        Ok(parse_reclaimed(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// Compact a sparse image.
+///
+/// Shorthand for `Compact::new(image).compact()`.
+pub fn compact<P: Into<PathBuf>>(image: P) -> io::Result<Option<u64>> {
+    Compact::new(image).compact()
+}
+
+// Looks for hdiutil's "Reclaimed 1.2 GB out of 3.4 GB possible." line
+pub(crate) fn parse_reclaimed(stdout: &str) -> Option<u64> {
+    let rest = &stdout[stdout.find("Reclaimed ")? + "Reclaimed ".len()..];
+    let mut words = rest.split_whitespace();
+    let count: f64 = words.next()?.parse().ok()?;
+    let unit: u64 = match words.next()? {
+        "byte" | "bytes" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        "TB" => 1 << 40,
+        _ => return None,
+    };
+    Some((count * unit as f64) as u64)
+}
+
+/// Builder to verify the checksum of a disk image.
+#[must_use = "builders do nothing unless `verify()` is called"]
+pub struct Verify {
+    image: PathBuf,
+    verbosity: Verbosity,
+    timeout: Option<Duration>,
+}
+
+impl Verify {
+    /// Creates a new builder verifying `image`.
+    pub fn new<P: Into<PathBuf>>(image: P) -> Verify {
+        Verify {
+            image: image.into(),
+            verbosity: Verbosity::Default,
+            timeout: None,
+        }
+    }
+
+    /// Ask hdiutil to print as little as possible.
+    pub fn quiet(mut self) -> Verify {
+        self.verbosity = Verbosity::Quiet;
+        self
+    }
+
+    /// Ask hdiutil to print extra diagnostics, which are logged as warnings.
+    pub fn verbose(mut self) -> Verify {
+        self.verbosity = Verbosity::Verbose;
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if verifying takes longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Verify {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn build_command(&self) -> Command {
+        let mut cmd = hdiutil();
+        cmd.arg("verify");
+        self.verbosity.add_args(&mut cmd);
+        cmd.arg(&self.image);
+        cmd
+    }
+
+    /// Verify the image, failing if its checksum does not match.
+    ///
+    /// Failures are reported as `DmgError::VerifyFailed`, classified by hdiutil's message.
+    pub fn verify(self) -> io::Result<()> {
+        let output = output_with_progress(&mut self.build_command(), None, None, self.timeout)?;
+        if output.status.success() {
+            return check_status(output).map(|_| ());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        Err(DmgError::VerifyFailed { status: output.status, failure: parse_verify_failure(&stderr), stderr }.into())
+    }
+}
+
+/// Why an image failed to verify, from [`Verify::verify()`](struct.Verify.html#method.verify).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyFailure {
+    /// The image data does not match its checksum, it may be damaged or incompletely downloaded.
+    ChecksumMismatch,
+    /// The image could not be read.
+    Io,
+    /// The file is not a disk image hdiutil understands, or is corrupt.
+    Format,
+    /// hdiutil failed for another reason, see its message.
+    Other,
+}
+
+// A best effort match on hdiutil's messages, like "checksum of "Test.dmg" is INVALID"
+pub(crate) fn parse_verify_failure(stderr: &str) -> VerifyFailure {
+    let stderr = stderr.to_lowercase();
+    let has = |needle| stderr.contains(needle);
+    if has("checksum") && (has("invalid") || has("mismatch") || has("failed")) {
+        VerifyFailure::ChecksumMismatch
+    } else if has("input/output error") || has("i/o error") {
+        VerifyFailure::Io
+    } else if has("not recognized") || has("corrupt") {
+        VerifyFailure::Format
+    } else {
+        VerifyFailure::Other
+    }
+}
+
+/// Verify the checksum of an image without attaching it.
+///
+/// Shorthand for `Verify::new(image).verify()`.
+pub fn verify<P: Into<PathBuf>>(image: P) -> io::Result<()> {
+    Verify::new(image).verify()
+}
+
+/// Metadata about a disk image, from [`imageinfo()`](fn.imageinfo.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Image format name, such as `UDZO`.
+    pub format: String,
+
+    /// Total size of the image contents in bytes.
+    pub size_bytes: u64,
+
+    /// Checksum algorithm, such as `CRC32`, if the image has a checksum.
+    pub checksum_type: Option<String>,
+
+    /// Partition scheme, such as `GUID`, if the image is partitioned.
+    pub partition_scheme: Option<String>,
+
+    /// Whether the image is encrypted.
+    pub encrypted: bool,
+}
+
+pub(crate) fn parse_image_info(plist: &Value) -> io::Result<ImageInfo> {
+    let info = check!(plist.as_dictionary(), "Format");
+    let size = check!(info, "Size Information", as_dictionary);
+    let string = |value: Option<&Value>| value.and_then(Value::as_string).map(String::from);
+    Ok(ImageInfo {
+        format: String::from(check!(info, "Format", as_string)),
+        size_bytes: check!(size, "Total Bytes", as_unsigned_integer),
+        checksum_type: string(info.get("Checksum Type")),
+        partition_scheme: string(info.get("partitions")
+            .and_then(Value::as_dictionary)
+            .and_then(|partitions| partitions.get("partition-scheme"))),
+        encrypted: info.get("Properties")
+            .and_then(Value::as_dictionary)
+            .and_then(|properties| properties.get("Encrypted"))
+            .and_then(Value::as_boolean)
+            .unwrap_or(false),
+    })
+}
+
+/// Inspect an image without attaching it.
+pub fn imageinfo<P: AsRef<Path>>(image: P) -> io::Result<ImageInfo> {
+    let mut cmd = hdiutil();
+    cmd.arg("imageinfo");
+    cmd.arg("-plist");
+    cmd.arg(image.as_ref());
+    parse_image_info(&run_plist(&mut cmd)?)
+}
+
+/// A volume inside an image, from [`volumes()`](fn.volumes.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    /// Number of the partition holding the volume.
+    pub partition: u64,
+
+    /// Volume name, such as `Test`.
+    pub name: String,
+
+    /// Filesystem as named by hdiutil, such as `HFS+` or `APFS`.
+    pub filesystem: String,
+
+    /// Partition type, such as `Apple_HFS`.
+    pub hint: Option<String>,
+
+    /// Size of the partition in bytes.
+    pub size_bytes: u64,
+}
+
+pub(crate) fn parse_volumes(plist: &Value) -> io::Result<Vec<VolumeInfo>> {
+    let info = check!(plist.as_dictionary(), "partitions");
+    let partitions = check!(info, "partitions", as_dictionary);
+    let block_size = partitions.get("block-size").and_then(Value::as_unsigned_integer).unwrap_or(512);
+    let mut volumes = Vec::new();
+    for partition in check!(partitions, "partitions", as_array) {
+        let partition = check!(partition.as_dictionary(), "partition-number");
+        // Only partitions hdiutil recognised a filesystem on hold volumes
+        let filesystems = match partition.get("partition-filesystems").and_then(Value::as_dictionary) {
+            Some(filesystems) => filesystems,
+            None => continue,
+        };
+        for (filesystem, name) in filesystems {
+            volumes.push(VolumeInfo {
+                partition: check!(partition, "partition-number", as_unsigned_integer),
+                name: String::from(name.as_string().unwrap_or_default()),
+                filesystem: filesystem.clone(),
+                hint: partition.get("partition-hint").and_then(Value::as_string).map(String::from),
+                size_bytes: check!(partition, "partition-length", as_unsigned_integer) * block_size,
+            });
+        }
+    }
+    Ok(volumes)
+}
+
+/// List the volumes inside an image without attaching it.
+///
+/// Unlike the volumes of an attached image, this works for images that cannot be mounted, and
+/// helps decide where to mount them beforehand.
+pub fn volumes<P: AsRef<Path>>(image: P) -> io::Result<Vec<VolumeInfo>> {
+    let mut cmd = hdiutil();
+    cmd.arg("imageinfo");
+    cmd.arg("-plist");
+    cmd.arg(image.as_ref());
+    parse_volumes(&run_plist(&mut cmd)?)
+}
+
+/// Move an image's resource fork data into its data fork.
+///
+/// Do this before copying an image to a filesystem that does not preserve resource forks.
+pub fn flatten<P: AsRef<Path>>(image: P) -> io::Result<()> {
+    let mut cmd = hdiutil();
+    cmd.arg("flatten");
+    cmd.arg(image.as_ref());
+    run(&mut cmd).map(|_| ())
+}
+
+/// Restore an image's resource fork from its data fork, the reverse of [`flatten()`](fn.flatten.html).
+pub fn unflatten<P: AsRef<Path>>(image: P) -> io::Result<()> {
+    let mut cmd = hdiutil();
+    cmd.arg("unflatten");
+    cmd.arg(image.as_ref());
+    run(&mut cmd).map(|_| ())
+}
+
+/// Embed the resources described in the XML file `resources` into an image, such as the
+/// software license agreement shown when it is attached.
+///
+/// The image should be flattened and not compressed afterwards. Apple marks `udifrez` as
+/// deprecated, newer hdiutil versions may not have it.
+pub fn apply_resources<P: AsRef<Path>, R: AsRef<Path>>(image: P, resources: R) -> io::Result<()> {
+    let mut cmd = hdiutil();
+    cmd.arg("udifrez");
+    cmd.arg("-xml");
+    cmd.arg(resources.as_ref());
+    cmd.arg(image.as_ref());
+    run(&mut cmd).map(|_| ())
+}
+
+/// Extract the resources embedded in an image as XML, in the format
+/// [`apply_resources()`](fn.apply_resources.html) reads.
+pub fn extract_resources<P: AsRef<Path>>(image: P) -> io::Result<Vec<u8>> {
+    let mut cmd = hdiutil();
+    cmd.arg("udifderez");
+    cmd.arg("-xml");
+    cmd.arg(image.as_ref());
+    run(&mut cmd).map(|output| output.stdout)
+}
+
+/// Checksum algorithms supported by [`checksum()`](fn.checksum.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    /// CRC-28.
+    Crc28,
+    /// CRC-32.
+    Crc32,
+    /// MD5.
+    Md5,
+    /// SHA-1.
+    Sha1,
+    /// SHA-256.
+    Sha256,
+    /// SHA-384.
+    Sha384,
+    /// SHA-512.
+    Sha512,
+    /// CRC-32 of the UDIF image data, as stored in the image itself.
+    UdifCrc32,
+    /// MD5 of the UDIF image data, as stored in the image itself.
+    UdifMd5,
+}
+
+impl ChecksumType {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            ChecksumType::Crc28 => "CRC28",
+            ChecksumType::Crc32 => "CRC32",
+            ChecksumType::Md5 => "MD5",
+            ChecksumType::Sha1 => "SHA1",
+            ChecksumType::Sha256 => "SHA256",
+            ChecksumType::Sha384 => "SHA384",
+            ChecksumType::Sha512 => "SHA512",
+            ChecksumType::UdifCrc32 => "UDIF-CRC32",
+            ChecksumType::UdifMd5 => "UDIF-MD5",
+        }
+    }
+}
+
+// Looks for hdiutil's "calculated CRC32 checksum = $2AD2BE59" line
+pub(crate) fn parse_checksum(stdout: &str) -> Option<String> {
+    let line = stdout.lines().rev().find(|line| line.contains("checksum ="))?;
+    let value = line[line.find('=')? + 1..].trim().trim_start_matches('$');
+    if value.is_empty() {
+        None
+    } else {
+        Some(String::from(value))
+    }
+}
+
+/// Compute the checksum of an image's contents, returned as hdiutil prints it in hexadecimal.
+pub fn checksum<P: AsRef<Path>>(image: P, checksum_type: ChecksumType) -> io::Result<String> {
+    let mut cmd = hdiutil();
+    cmd.arg("checksum");
+    cmd.arg("-type");
+    cmd.arg(checksum_type.type_name());
+    cmd.arg(image.as_ref());
+
+    let output = run(&mut cmd)?;
+    match parse_checksum(&String::from_utf8_lossy(&output.stdout)) {
+        Some(checksum) => Ok(checksum),
+        None => Err(DmgError::UnexpectedOutput("checksum").into()),
+    }
+}
+
+/// A partition map entry, from [`partition_map()`](fn.partition_map.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Index in the partition map, negative for synthesized entries like the MBR.
+    pub index: i64,
+
+    /// Partition type, such as `Apple_HFS` or `Primary GPT Header`.
+    pub partition_type: String,
+
+    /// Partition name, often empty.
+    pub name: String,
+
+    /// First 512-byte sector of the partition.
+    pub start_sector: u64,
+
+    /// Number of 512-byte sectors in the partition.
+    pub length_sectors: u64,
+}
+
+// Reads the table under a header like "## Dev___ Type___ Name___ Start___ Size___ End___", where
+// the underscores give the width of the text columns and the numbers are right aligned
+pub(crate) fn parse_partition_map(stdout: &str) -> Option<Vec<Partition>> {
+    let mut lines = stdout.lines().skip_while(|line| !line.trim_start().starts_with("##"));
+    let header = lines.next()?;
+    let column = |name| header.find(name);
+    let (type_column, name_column) = (column("Type")?, column("Name")?);
+    let numbers = header[name_column..].split_whitespace().count() - 1;
+
+    let mut partitions = Vec::new();
+    for line in lines.take_while(|line| !line.trim().is_empty()) {
+        // Split the numbers off the end, leaving the text columns in place
+        let mut text = line.trim_end();
+        let mut values = Vec::with_capacity(numbers);
+        for _ in 0..numbers {
+            let split = text.rfind(char::is_whitespace).map_or(0, |split| split + 1);
+            values.push(text[split..].parse().ok()?);
+            text = text[..split].trim_end();
+        }
+        values.reverse();
+        // Text columns can be cut short when the rest of the line is blank
+        let slice = |start: usize, end: usize| {
+            text.get(start.min(text.len())..end.min(text.len())).map(|column| String::from(column.trim()))
+        };
+        partitions.push(Partition {
+            index: text.split_whitespace().next()?.parse().ok()?,
+            partition_type: slice(type_column, name_column)?,
+            name: slice(name_column, text.len())?,
+            start_sector: *values.first()?,
+            length_sectors: *values.get(1)?,
+        });
+    }
+    Some(partitions)
+}
+
+/// Read the partition map of an image or device.
+pub fn partition_map<P: AsRef<Path>>(path: P) -> io::Result<Vec<Partition>> {
+    let mut cmd = hdiutil();
+    cmd.arg("pmap");
+    cmd.arg(path.as_ref());
+
+    let output = run(&mut cmd)?;
+    match parse_partition_map(&String::from_utf8_lossy(&output.stdout)) {
+        Some(partitions) => Ok(partitions),
+        None => Err(DmgError::UnexpectedOutput("pmap output").into()),
+    }
+}
+
+/// Change the passphrase of an encrypted image from `old` to `new`.
+///
+/// Both passphrases are passed to hdiutil over stdin. Fails with `DmgError::IncorrectPassphrase`
+/// if `old` is rejected.
+pub fn change_passphrase<P, O, N>(image: P, old: O, new: N) -> io::Result<()>
+        where P: AsRef<Path>, O: Into<Passphrase>, N: Into<Passphrase> {
+    let mut cmd = hdiutil();
+    cmd.arg("chpass");
+    cmd.arg("-oldstdinpass");
+    cmd.arg("-newstdinpass");
+    cmd.arg(image.as_ref());
+
+    // hdiutil expects the old passphrase first, terminated by a NUL
+    let input = Passphrase(format!("{}\0{}", old.into().0, new.into().0));
+    check_passphrase(output_with_input(&mut cmd, Some(input.as_bytes()))?).map(|_| ())
+}
+
+/// Builder to burn a disk image to optical media.
+#[must_use = "builders do nothing unless `burn()` is called"]
+pub struct Burn {
+    image: PathBuf,
+    device: Option<PathBuf>,
+    verify: Option<bool>,
+    eject: Option<bool>,
+    test_burn: bool,
+    timeout: Option<Duration>,
+}
+
+impl Burn {
+    /// Creates a new builder burning `image` to the first available burner.
+    pub fn new<P: Into<PathBuf>>(image: P) -> Burn {
+        Burn {
+            image: image.into(),
+            device: None,
+            verify: None,
+            eject: None,
+            test_burn: false,
+            timeout: None,
+        }
+    }
+
+    /// Burn with the given device, as listed by `hdiutil burn -list`.
+    pub fn device<P: Into<PathBuf>>(mut self, device: P) -> Burn {
+        self.device = Some(device.into());
+        self
+    }
+
+    /// Whether to verify the disc after burning, hdiutil does by default.
+    pub fn verify(mut self, enabled: bool) -> Burn {
+        self.verify = Some(enabled);
+        self
+    }
+
+    /// Whether to eject the disc after burning, hdiutil does by default.
+    pub fn eject(mut self, enabled: bool) -> Burn {
+        self.eject = Some(enabled);
+        self
+    }
+
+    /// Simulate the burn with the laser turned off.
+    pub fn test_burn(mut self) -> Burn {
+        self.test_burn = true;
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if burning takes longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Burn {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn build_command(&self) -> Command {
+        let mut cmd = hdiutil();
+        cmd.arg("burn");
+        if let Some(ref device) = self.device {
+            cmd.arg("-device");
+            cmd.arg(device);
+        }
+        if let Some(verify) = self.verify {
+            cmd.arg(if verify { "-verifyburn" } else { "-noverifyburn" });
+        }
+        if let Some(eject) = self.eject {
+            cmd.arg(if eject { "-eject" } else { "-noeject" });
+        }
+        if self.test_burn {
+            cmd.arg("-testburn");
+        }
+        cmd.arg(&self.image);
+        cmd
+    }
+
+    /// Burn the image.
+    pub fn burn(self) -> io::Result<()> {
+        check_status(output_with_progress(&mut self.build_command(), None, None, self.timeout)?).map(|_| ())
+    }
+}
+
+/// Burn an image to optical media using `device`.
+///
+/// Shorthand for `Burn::new(image).device(device).burn()`.
+pub fn burn<P: Into<PathBuf>, D: Into<PathBuf>>(image: P, device: D) -> io::Result<()> {
+    Burn::new(image).device(device).burn()
+}
+
+/// Mark an image to be expanded automatically after it is downloaded, or clear the mark.
+///
+/// Only older versions of macOS honour the flag, recent versions of hdiutil reject the command.
+pub fn internet_enable<P: AsRef<Path>>(image: P, enabled: bool) -> io::Result<()> {
+    let mut cmd = hdiutil();
+    cmd.arg("internet-enable");
+    cmd.arg(if enabled { "-yes" } else { "-no" });
+    cmd.arg(image.as_ref());
+    run(&mut cmd).map(|_| ())
+}
+
+// Looks for the "YES" or "NO" ending a line like "internet-enable: YES"
+pub(crate) fn parse_internet_enabled(stdout: &str) -> Option<bool> {
+    stdout.lines().find_map(|line| match line.rsplit(|c: char| c == ':' || c.is_whitespace()).next()? {
+        "YES" => Some(true),
+        "NO" => Some(false),
+        _ => None,
+    })
+}
+
+/// Check whether an image is marked with [`internet_enable()`](fn.internet_enable.html).
+pub fn is_internet_enabled<P: AsRef<Path>>(image: P) -> io::Result<bool> {
+    let mut cmd = hdiutil();
+    cmd.arg("internet-enable");
+    cmd.arg("-query");
+    cmd.arg(image.as_ref());
+
+    let output = run(&mut cmd)?;
+    match parse_internet_enabled(&String::from_utf8_lossy(&output.stdout)) {
+        Some(enabled) => Ok(enabled),
+        None => Err(DmgError::UnexpectedOutput("internet-enable output").into()),
+    }
+}
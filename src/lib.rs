@@ -36,25 +36,214 @@
 //! dmg::detach("/Volumes/Test", false).expect("could not detach"); // Do not force detach
 //! ```
 //!
+//! To create a new, empty 200 MB image and attach it:
+//!
+//! ```rust,no_run
+//! use dmg::Attach;
+//! use dmg::create::{BlankImage, Size};
+//!
+//! let image = BlankImage::new(Size::mib(200))
+//!     .volume_name("Scratch")
+//!     .create_temp()
+//!     .expect("could not create");
+//! let info = Attach::new(&*image).with().expect("could not attach");
+//! // Image deleted when 'image' dropped
+//! ```
+//!
+//! With the `async` feature enabled the slow operations also have `_async` variants built on
+//! tokio, such as [`Attach::attach_async()`](struct.Attach.html#method.attach_async).
+//!
+//! Attaching or detaching many images at once can upset Disk Arbitration. Enable the opt-in
+//! `global-lock` feature to have this crate run at most one blocking attach or detach at a time
+//! across the whole process. The `_async` variants are not serialized.
+//!
+//! Disk images are only supported on macOS, on other platforms everything compiles but fails
+//! with [`DmgError::Unsupported`](enum.DmgError.html#variant.Unsupported).
+//!
+//! Set the `DMG_HDIUTIL` environment variable to run a different `hdiutil` binary, for example
+//! a mock script in tests. This also lifts the macOS restriction.
+//!
 //! For more examples see [`src/tests.rs`][1] and [`src/bin/demo.rs`][2]
 //!
 //!
 //! [1]: https://github.com/mgoszcz2/dmg/blob/master/src/tests.rs
 //! [2]: https://github.com/mgoszcz2/dmg/blob/master/src/bin/demo.rs
 
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::io::{self, ErrorKind, Cursor};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
 use std::ops::Deref;
-use std::env;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use std::{cmp, env, fmt, fs, ptr, thread};
 
-use log::info;
+use log::{error, info, warn};
 use plist::Value;
+use serde::Deserialize;
+
+use create::{image_key_arg, Size};
+
+macro_rules! check {
+    ($opt:expr, $name:expr) => {
+        match $opt {
+            Some(res) => res,
+            None => return Err(DmgError::MissingProperty($name).into()),
+        }
+    };
+    ($dict:expr, $key:expr, $as:ident) => {
+        check!($dict.get($key).and_then(Value::$as), $key)
+    }
+}
+
+/// Define an enum of hdiutil image formats named exactly like their variants.
+macro_rules! format_enum {
+    ($(#[$attr:meta])* pub enum $name:ident { $($(#[$vattr:meta])* $variant:ident,)* }) => {
+        $(#[$attr])*
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($(#[$vattr])* $variant,)*
+        }
+
+        impl $name {
+            pub(crate) fn format_name(&self) -> &'static str {
+                match *self {
+                    $($name::$variant => stringify!($variant),)*
+                }
+            }
+
+            /// File extension hdiutil gives images of this format, without the dot.
+            pub fn extension(&self) -> &'static str {
+                match self.format_name() {
+                    "UDSP" => "sparseimage",
+                    "UDSB" => "sparsebundle",
+                    "UDTO" => "cdr",
+                    _ => "dmg",
+                }
+            }
+        }
+
+        /// Parse a format from its hdiutil name, such as `UDZO`.
+        impl ::std::str::FromStr for $name {
+            type Err = $crate::DmgError;
+            fn from_str(name: &str) -> Result<$name, $crate::DmgError> {
+                match name {
+                    $(stringify!($variant) => Ok($name::$variant),)*
+                    _ => Err($crate::DmgError::UnknownFormat(name.to_string())),
+                }
+            }
+        }
+
+        /// Formats as the hdiutil name of the format.
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                f.write_str(self.format_name())
+            }
+        }
+    }
+}
+
+/// Define `extra_arg()` and `extra_args()` appending to the `Vec<OsString>` at `self.$field`.
+macro_rules! extra_args_fns {
+    ($($field:ident).+) => {
+        /// Pass an argument the builder does not model to hdiutil.
+        ///
+        /// Extra arguments come after every modeled option, in the order they were added, and before
+        /// the paths at the end of the command such as `-o <output>`. Paths hdiutil expects first,
+        /// like the source image of `convert`, stay in front of them. hdiutil is sensitive to
+        /// argument order, so check its manual for options that have to appear in a particular place.
+        pub fn extra_arg<S: Into<OsString>>(mut self, arg: S) -> Self {
+            self.$($field).+.push(arg.into());
+            self
+        }
+
+        /// Pass several arguments the builder does not model to hdiutil.
+        ///
+        /// See [`extra_arg()`](#method.extra_arg).
+        pub fn extra_args<I, S>(mut self, args: I) -> Self where I: IntoIterator<Item = S>, S: Into<OsString> {
+            self.$($field).+.extend(args.into_iter().map(Into::into));
+            self
+        }
+    }
+}
+
+macro_rules! library_fn {
+    ($($field:ident).+) => {
+        /// Have hdiutil use the image library at `path`, passed as `-library`.
+        pub fn library<P: Into<PathBuf>>(mut self, path: P) -> Self {
+            self.$($field).+ = Some(path.into());
+            self
+        }
+    }
+}
+
+macro_rules! key_value_fn {
+    ($doc:expr, $flag:expr, $name:ident, $($field:ident).+) => {
+        #[doc = concat!($doc, ", passed as `", $flag, " key=value`.")]
+        ///
+        /// Can be called repeatedly to set several keys.
+        pub fn $name<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+            self.$($field).+.push(image_key_arg(key.as_ref(), value.as_ref()));
+            self
+        }
+    }
+}
+
+mod error;
+pub mod convert;
+pub mod create;
+pub mod image;
+
+pub use error::DmgError;
 
 #[cfg(test)]
 mod tests;
 
 static DISK_COMMAND: &str = "hdiutil";
+/// How long to wait for mount points hdiutil reported to show up, see `Attach::mount_wait()`.
+const DEFAULT_MOUNT_WAIT: Duration = Duration::from_secs(2);
+static DISK_COMMAND_ENV: &str = "DMG_HDIUTIL";
+
+/// Create a new hdiutil command, pass errors spawning it through `spawn_error()`.
+///
+/// The `DMG_HDIUTIL` environment variable overrides the binary used.
+fn hdiutil() -> Command {
+    Command::new(env::var_os(DISK_COMMAND_ENV).unwrap_or_else(|| DISK_COMMAND.into()))
+}
+
+/// hdiutil only exists on macOS, fail before trying to spawn it anywhere else.
+#[cfg(target_os = "macos")]
+fn check_platform() -> Result<(), DmgError> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn check_platform() -> Result<(), DmgError> {
+    // Allow running against a stand-in binary anywhere
+    if env::var_os(DISK_COMMAND_ENV).is_some() {
+        return Ok(());
+    }
+    Err(DmgError::Unsupported)
+}
+
+/// Held while attaching or detaching with the `global-lock` feature enabled.
+static GLOBAL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Wait for any other attach or detach in this process to finish, if serializing them.
+fn global_lock() -> Option<MutexGuard<'static, ()>> {
+    // Only the order of hdiutil calls is guarded, a panic while holding the lock is harmless
+    cfg!(feature = "global-lock").then(|| GLOBAL_LOCK.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+fn spawn_error(err: io::Error) -> DmgError {
+    if err.kind() == io::ErrorKind::NotFound {
+        DmgError::HdiutilNotFound(env::var_os(DISK_COMMAND_ENV).map(PathBuf::from))
+    } else {
+        DmgError::Spawn(err)
+    }
+}
 
 enum Mount {
     Default,
@@ -63,12 +252,73 @@ enum Mount {
     Point(PathBuf)
 }
 
+enum Shadow {
+    None,
+    Path(PathBuf),
+    Temp,
+}
+
+enum MountPolicy {
+    Default,
+    Required,
+    Optional,
+    Suppressed,
+}
+
+enum Access {
+    Default,
+    ReadOnly,
+    ReadWrite,
+}
+
+/// How much hdiutil itself prints, independent of this crate's logging.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Verbosity {
+    Default,
+    Quiet,
+    Verbose,
+}
+
+impl Verbosity {
+    pub(crate) fn add_args(&self, cmd: &mut Command) {
+        match *self {
+            Verbosity::Default => {},
+            Verbosity::Quiet => {
+                cmd.arg("-quiet");
+            },
+            Verbosity::Verbose => {
+                cmd.arg("-verbose");
+            },
+        }
+    }
+}
+
 /// Builder to attach a disk image.
+#[must_use = "builders do nothing unless `attach()` or similar is called"]
 pub struct Attach {
     image: PathBuf,
     mount: Mount,
     hidden: bool,
-    force_readonly: bool,
+    no_verify: bool,
+    no_autofsck: bool,
+    no_idme: bool,
+    not_removable: bool,
+    private: bool,
+    access: Access,
+    shadow: Shadow,
+    passphrase: Option<Passphrase>,
+    owners: Option<bool>,
+    mount_policy: MountPolicy,
+    cache_size: Option<Size>,
+    verbosity: Verbosity,
+    timeout: Option<Duration>,
+    recover: Option<PathBuf>,
+    section: Option<(u64, u64)>,
+    drive_keys: Vec<OsString>,
+    mount_wait: Duration,
+    library: Option<PathBuf>,
+    extra_args: Vec<OsString>,
+    source: Option<Box<dyn Read + Send>>,
 }
 
 /// Data associated with an attached disk image.
@@ -79,44 +329,160 @@ pub struct Info {
 
     /// Device node path for this disk image.
     pub device: PathBuf,
+
+    /// Filesystem of the volume as reported by hdiutil, such as `hfs` or `apfs`.
+    pub filesystem: Option<String>,
+
+    /// Partition content hint, such as `Apple_HFS`.
+    pub content_hint: Option<String>,
+
+    /// Shadow file receiving writes to the image, if one was used.
+    pub shadow: Option<PathBuf>,
+
+    /// Whether the volume was mounted writable, `false` if hdiutil did not report it.
+    pub writable: bool,
+}
+
+/// All mounted volumes of an attached disk image.
+///
+/// Like a [`Handle`](struct.Handle.html), dropping it leaves the image attached, use
+/// [`detach()`](#method.detach) when done.
+///
+/// Created with [`attach_all()`](struct.Attach.html#method.attach_all)
+#[derive(Debug)]
+#[must_use = "dropping `Volumes` leaves the image attached"]
+pub struct Volumes {
+    /// Device node path for the whole disk image.
+    pub device: PathBuf,
+
+    /// Every volume with a mount point, in the order hdiutil reported them.
+    pub volumes: Vec<Info>,
+
+    /// Every device node of the image including the whole disk and unmounted slices.
+    pub entities: Vec<Entity>,
+}
+
+/// A device node of an attached disk image, such as `/dev/disk4s1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entity {
+    /// Device node path.
+    pub device: PathBuf,
+
+    /// Partition content hint, such as `GUID_partition_scheme` or `Apple_HFS`.
+    pub content_hint: Option<String>,
+
+    /// Path at which the entity is mounted, if it is.
+    pub mount_point: Option<PathBuf>,
+
+    /// Whether the entity holds a filesystem that could be mounted.
+    pub potentially_mountable: bool,
+}
+
+/// A disk image currently attached to the system, as reported by `hdiutil info`.
+///
+/// Created with [`attached_images()`](fn.attached_images.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachedImage {
+    /// Absolute path of the image file.
+    pub image_path: PathBuf,
+
+    /// Device node path for the whole disk image.
+    pub device: PathBuf,
+
+    /// Every device node of the image including the whole disk, with their mount points.
+    pub entities: Vec<Entity>,
+}
+
+/// Passphrase for an encrypted disk image.
+///
+/// The passphrase is passed to hdiutil over stdin and zeroed when dropped.
+pub struct Passphrase(String);
+
+impl Passphrase {
+    fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Passphrase {
+        Passphrase(passphrase)
+    }
+}
+
+impl<'a> From<&'a str> for Passphrase {
+    fn from(passphrase: &'a str) -> Passphrase {
+        Passphrase(String::from(passphrase))
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        // Zero bytes are valid UTF-8, volatile so the writes are not optimised away
+        for byte in unsafe { self.0.as_bytes_mut() } {
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Progress update from a long running hdiutil operation.
+///
+/// Passed to `on_progress()` callbacks on the create and convert builders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Percentage done from 0 to 100, negative while hdiutil cannot tell.
+    pub percent: f32,
+
+    /// Description of the current step, if this update announced a new one.
+    pub message: Option<String>,
 }
 
+type ProgressFn = dyn FnMut(Progress) + Send;
+
 /// Convinience handle for detaching an attached disk image.
 ///
+/// **Dropping a `Handle` leaves the image attached.** Returning early, for example with `?`,
+/// while a handle is in scope leaks the mount. Use [`auto_detach()`](#method.auto_detach) or
+/// [`Attach::with()`](struct.Attach.html#method.with) to detach on drop instead.
+///
 /// Created with [`attach()`](struct.Attach.html#method.attach)
 #[derive(Debug)]
+#[must_use = "dropping a `Handle` leaves the image attached"]
 pub struct Handle(Info);
 
 /// An attached disk image handle that detaches it when dropped.
 ///
+/// Drop never panics, errors detaching are only logged. To handle them yourself convert it with
+/// [`into_handle()`](#method.into_handle) and detach explicitly.
+///
 /// Created with [`with()`](struct.Attach.html#method.with)
 #[derive(Debug)]
-pub struct With(Info);
+#[must_use = "dropping a `With` detaches the image right away"]
+pub struct With(Option<Info>);
 
-macro_rules! check {
-    ($opt:expr) => {
-        match $opt {
-            Some(res) => res,
-            None => return Err(io::Error::new(ErrorKind::InvalidData, "could not find property")),
-        }
+/// Access the [`Info`](struct.Info.html) struct associated with this handle.
+impl Deref for Handle {
+    type Target = Info;
+    fn deref(&self) -> &Info {
+        &self.0
     }
 }
 
-macro_rules! deref_info {
-    ($name:ident) => {
-        /// Access the [`Info`](struct.Info.html) struct associated with this handle.
-        impl Deref for $name {
-            type Target = Info;
-            fn deref(&self) -> &Info {
-                &self.0
-            }
-        }
+/// Access the [`Info`](struct.Info.html) struct associated with this handle.
+impl Deref for With {
+    type Target = Info;
+    fn deref(&self) -> &Info {
+        // Only taken by into_handle(), which consumes the guard
+        self.0.as_ref().expect("info already taken")
     }
 }
 
-deref_info!(Handle);
-deref_info!(With);
-
 impl Handle {
     /// Detach the image, ignoring any open files.
     pub fn force_detach(self) -> io::Result<()> {
@@ -127,12 +493,166 @@ impl Handle {
     pub fn detach(self) -> io::Result<()> {
         detach(&self.device, false)
     }
+
+    /// Eject the image, see [`eject()`](fn.eject.html).
+    pub fn eject(self) -> io::Result<()> {
+        eject(&self.device, false)
+    }
+
+    /// Detach the image, retrying up to `attempts` times `delay` apart while the volume is busy
+    /// before forcing it.
+    ///
+    /// Useful when something like Spotlight briefly keeps the volume busy. Any other failure is
+    /// returned right away.
+    pub fn detach_retry(self, attempts: u32, delay: Duration) -> io::Result<()> {
+        for _ in 0..attempts {
+            match detach(&self.device, false) {
+                Err(ref err) if is_transient(err) => {
+                    info!("Detach failed, retrying: {}", err);
+                    thread::sleep(delay);
+                },
+                result => return result,
+            }
+        }
+        detach(&self.device, true)
+    }
+
+    /// Detach the image when the returned guard is dropped, like one from
+    /// [`Attach::with()`](struct.Attach.html#method.with).
+    pub fn auto_detach(self) -> With {
+        With(Some(self.0))
+    }
+
+    /// Give up the ability to detach, keeping the image's info.
+    pub fn into_info(self) -> Info {
+        self.0
+    }
+
+    /// Leave the image attached, for example to hand it off to another process.
+    ///
+    /// Same as [`into_info()`](#method.into_info), but states the intent to never detach.
+    pub fn leak(self) -> Info {
+        self.into_info()
+    }
+}
+
+impl Info {
+    /// Look up the mounted volume of an attached image by its device node, such as `/dev/disk4s1`.
+    ///
+    /// Fails with [`DmgError::NotMounted`](enum.DmgError.html) if the device is not a mounted
+    /// volume of any attached image.
+    pub fn from_device<P: AsRef<Path>>(device: P) -> io::Result<Info> {
+        parse_device_info(&run_info()?, device.as_ref())
+    }
+
+    /// Path of `path` inside the volume.
+    ///
+    /// Unlike `mount_point.join(path)`, an absolute `path` like `/Applications` is taken from the
+    /// root of the volume too.
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        self.mount_point.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// List the files at the root of the volume.
+    pub fn read_dir(&self) -> io::Result<fs::ReadDir> {
+        fs::read_dir(&self.mount_point)
+    }
+}
+
+impl Volumes {
+    /// Detach the whole image, ignoring any open files.
+    pub fn force_detach(self) -> io::Result<()> {
+        detach(&self.device, true)
+    }
+
+    /// Detach the whole image, unmounting all of its volumes.
+    pub fn detach(self) -> io::Result<()> {
+        detach(&self.device, false)
+    }
+}
+
+impl With {
+    /// Stop detaching on drop, returning a handle to detach explicitly.
+    pub fn into_handle(mut self) -> Handle {
+        // Leaves nothing for drop to detach
+        Handle(self.0.take().expect("info already taken"))
+    }
+
+    /// Leave the image attached instead of detaching on drop.
+    pub fn leak(self) -> Info {
+        self.into_handle().leak()
+    }
+
+    /// Attach an image stored inside this one, at `path` relative to the mount point.
+    ///
+    /// `options` configures how the inner image is attached, for example
+    /// `|attach| attach.hidden()`. Both images stay attached until the returned
+    /// [`Nested`](struct.Nested.html) is dropped. If the inner image cannot be attached this one
+    /// is detached.
+    pub fn attach_nested<P: AsRef<Path>, F: FnOnce(Attach) -> Attach>(self, path: P, options: F)
+            -> io::Result<Nested> {
+        let inner = options(Attach::new(self.join(path))).with()?;
+        Ok(Nested { inner, outer: self })
+    }
+}
+
+/// An image attached from inside another attached image, like an installer shipped in a dmg.
+///
+/// Dropping it detaches the inner image, then the outer one. Dereferences to the
+/// [`Info`](struct.Info.html) of the inner image.
+///
+/// Created with [`With::attach_nested()`](struct.With.html#method.attach_nested).
+#[derive(Debug)]
+#[must_use = "dropping a `Nested` detaches both images right away"]
+pub struct Nested {
+    // Fields drop in order, so the inner image is always detached first
+    inner: With,
+    outer: With,
 }
 
-/// Detach the disk image on drop
+impl Nested {
+    /// The image the inner image is stored in.
+    pub fn outer(&self) -> &Info {
+        &self.outer
+    }
+
+    /// Detach both images, reporting any error detaching them.
+    ///
+    /// If the inner image fails to detach both are left attached, the outer one cannot be
+    /// detached before it.
+    pub fn detach(self) -> io::Result<()> {
+        let (inner, outer) = (self.inner.into_handle(), self.outer.into_handle());
+        inner.detach()?;
+        outer.detach()
+    }
+}
+
+/// Access the [`Info`](struct.Info.html) of the inner image.
+impl Deref for Nested {
+    type Target = Info;
+    fn deref(&self) -> &Info {
+        &self.inner
+    }
+}
+
+/// Detach the disk image on drop, forcing it if needed
 impl Drop for With {
     fn drop(&mut self) {
-        detach(&self.device, false).expect("could not detach");
+        let info = match self.0 {
+            Some(ref info) => info,
+            None => return,
+        };
+        if let Err(err) = detach(&info.device, false) {
+            if let Some(DmgError::NotAttached(_)) = DmgError::from_io_error(&err) {
+                info!("Already detached: {:?}", info.device);
+                return;
+            }
+            info!("Detach failed, forcing: {}", err);
+            if let Err(err) = detach(&info.device, true) {
+                error!("Could not detach {:?}: {}", info.device, err);
+            }
+        }
     }
 }
 
@@ -163,7 +683,39 @@ impl Attach {
             image: path.into(),
             mount: Mount::Default,
             hidden: false,
-            force_readonly: false,
+            no_verify: false,
+            no_autofsck: false,
+            no_idme: false,
+            not_removable: false,
+            private: false,
+            access: Access::Default,
+            shadow: Shadow::None,
+            passphrase: None,
+            owners: None,
+            mount_policy: MountPolicy::Default,
+            cache_size: None,
+            verbosity: Verbosity::Default,
+            timeout: None,
+            recover: None,
+            section: None,
+            drive_keys: Vec::new(),
+            mount_wait: DEFAULT_MOUNT_WAIT,
+            library: None,
+            extra_args: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// Creates a new builder attaching an image streamed from `reader`, such as a download.
+    ///
+    /// hdiutil reads the image from its stdin, so it never touches the disk. This cannot be
+    /// combined with [`passphrase()`](#method.passphrase), which uses stdin too, nor with
+    /// [`attach_async()`](#method.attach_async). A streamed image is only read once, so
+    /// [`attach_with_retries()`](#method.attach_with_retries) does not retry it.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Attach {
+        Attach {
+            source: Some(Box::new(reader)),
+            ..Attach::new("-")
         }
     }
 
@@ -172,7 +724,169 @@ impl Attach {
     mount_fn!("Asuming only one volume, mount it at path instead of in `/Volumes`.", mount_point, Point);
     mount_fn!("Mount under `path` with a random unique mount point directory name.", mount_random, Random);
     enable_fn!("Render the volume invisible in applications like Finder.", hidden);
-    enable_fn!("Force the device to be read-only.", force_readonly);
+    enable_fn!("Skip verifying the image checksum, faster for trusted images.", no_verify);
+    enable_fn!("Skip the automatic filesystem check on mount.", no_autofsck);
+
+    /// Skip IDME processing, where an image asks to run actions like copying its contents out.
+    ///
+    /// Internet-enabled images use IDME to unpack themselves when first attached. Use this on
+    /// untrusted images so attaching them never causes anything but the attach itself.
+    pub fn no_idme(mut self) -> Attach {
+        self.no_idme = true;
+        self
+    }
+
+    /// Prevent the image from being ejected, for example from Finder, until it is detached.
+    ///
+    /// Only works when running as root. Detaching with this crate, which calls
+    /// `hdiutil detach`, still works.
+    pub fn not_removable(mut self) -> Attach {
+        self.not_removable = true;
+        self
+    }
+
+    /// Keep the volume private to this process by not notifying Disk Arbitration.
+    ///
+    /// Unlike [`hidden()`](#method.hidden) other applications do not see the volume at all.
+    /// Disk Arbitration will never eject a private volume, it must be detached explicitly.
+    pub fn private(mut self) -> Attach {
+        self.private = true;
+        self
+    }
+
+    /// Force the device to be read-only.
+    ///
+    /// Overrides an earlier call to [`read_write()`](#method.read_write).
+    pub fn force_readonly(mut self) -> Attach {
+        self.access = Access::ReadOnly;
+        self
+    }
+
+    /// Force the device to be read/write, for example when writes go to a shadow file.
+    ///
+    /// Overrides an earlier call to [`force_readonly()`](#method.force_readonly).
+    pub fn read_write(mut self) -> Attach {
+        self.access = Access::ReadWrite;
+        self
+    }
+
+    /// Redirect writes to a shadow file at `path`, leaving the image untouched.
+    ///
+    /// The shadow file is created if it does not exist yet.
+    pub fn shadow<P: Into<PathBuf>>(mut self, path: P) -> Attach {
+        self.shadow = Shadow::Path(path.into());
+        self
+    }
+
+    /// Redirect writes to a new shadow file in its own directory inside the temporary directory.
+    ///
+    /// The path is available as [`Info::shadow`](struct.Info.html#structfield.shadow). Neither
+    /// the file nor its directory is deleted after detaching, remove the directory when done.
+    pub fn shadow_temp(mut self) -> Attach {
+        self.shadow = Shadow::Temp;
+        self
+    }
+
+    /// Passphrase for an encrypted image, passed to hdiutil over stdin.
+    ///
+    /// If hdiutil rejects the passphrase attaching fails with
+    /// [`DmgError::IncorrectPassphrase`](enum.DmgError.html#variant.IncorrectPassphrase).
+    pub fn passphrase<P: Into<Passphrase>>(mut self, passphrase: P) -> Attach {
+        self.passphrase = Some(passphrase.into());
+        self
+    }
+
+    /// Require every mountable volume to mount, failing the attach otherwise.
+    ///
+    /// Fails with [`DmgError::NotMounted`](enum.DmgError.html#variant.NotMounted) if hdiutil
+    /// returns without mounting one of them, after detaching the image again.
+    pub fn mount_required(mut self) -> Attach {
+        self.mount_policy = MountPolicy::Required;
+        self
+    }
+
+    /// Mount the volumes, but still attach the image if some of them fail to mount.
+    ///
+    /// Use [`attach_all()`](#method.attach_all) to see which volumes did mount.
+    pub fn mount_optional(mut self) -> Attach {
+        self.mount_policy = MountPolicy::Optional;
+        self
+    }
+
+    /// Attach the image without mounting any volumes, same as hdiutil's `-nomount`.
+    ///
+    /// With nothing mounted only [`attach_all()`](#method.attach_all) succeeds, use its device
+    /// nodes to for example fsck a volume before mounting it with
+    /// [`mount_volume()`](fn.mount_volume.html).
+    pub fn mount_suppressed(mut self) -> Attach {
+        self.mount_policy = MountPolicy::Suppressed;
+        self
+    }
+
+    /// Whether to honour file ownership on the mounted volume.
+    ///
+    /// When not set the volume's own setting is used.
+    pub fn owners(mut self, enabled: bool) -> Attach {
+        self.owners = Some(enabled);
+        self
+    }
+
+    /// Ask hdiutil to print extra diagnostics, which are logged as warnings.
+    ///
+    /// There is no `quiet()`, hdiutil's `-quiet` suppresses the plist attaching relies on.
+    pub fn verbose(mut self) -> Attach {
+        self.verbosity = Verbosity::Verbose;
+        self
+    }
+
+    /// Attach a damaged image using the recovery keychain or plist at `path`.
+    ///
+    /// Recovery skips integrity checks meant to reject the image, so pair it with
+    /// [`no_verify()`](#method.no_verify) for images failing their checksum and treat the data
+    /// read as untrusted.
+    pub fn recover<P: Into<PathBuf>>(mut self, path: P) -> Attach {
+        self.recover = Some(path.into());
+        self
+    }
+
+    extra_args_fns!(extra_args);
+
+    /// Attach only `length` 512-byte sectors of the image starting at sector `offset`.
+    pub fn section(mut self, offset: u64, length: u64) -> Attach {
+        self.section = Some((offset, length));
+        self
+    }
+
+    /// Wait up to `wait` for the mount points hdiutil reported to exist, two seconds by default.
+    ///
+    /// Disk Arbitration can finish mounting a volume after hdiutil returns, so reading the mount
+    /// point right away could fail. If a mount point is still missing afterwards the image is
+    /// detached again and attaching fails with `DmgError::NotMounted`. Pass `Duration::ZERO` to
+    /// only check once. Only applies to the blocking methods.
+    pub fn mount_wait(mut self, wait: Duration) -> Attach {
+        self.mount_wait = wait;
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if attaching takes longer than `timeout`.
+    ///
+    /// Only applies to the blocking methods.
+    pub fn timeout(mut self, timeout: Duration) -> Attach {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the size of the cache hdiutil keeps for the image.
+    ///
+    /// Tuning this can speed up streaming reads from large images.
+    pub fn cache_size<S: Into<Size>>(mut self, size: S) -> Attach {
+        self.cache_size = Some(size.into());
+        self
+    }
+
+    library_fn!(library);
+    key_value_fn!("Set a property of the disk image driver such as `system-image`", "-drivekey",
+        drive_key, drive_keys);
 
     /// Mount in a random folder inside the temporary directory.
     ///
@@ -181,8 +895,35 @@ impl Attach {
         self.mount_random(env::temp_dir())
     }
 
-    fn attach_info(self) -> io::Result<Info> {
-        let mut cmd = Command::new(DISK_COMMAND);
+    /// The shadow file to pass hdiutil, keeping a temporary one's directory around if `keep`.
+    fn shadow_path(&self, keep: bool) -> io::Result<Option<PathBuf>> {
+        match self.shadow {
+            Shadow::None => Ok(None),
+            Shadow::Path(ref path) => Ok(Some(path.clone())),
+            Shadow::Temp => {
+                // hdiutil insists on creating the shadow file itself, so give it a fresh private
+                // directory nobody else can create the file in first
+                let dir = tempfile::Builder::new().prefix("dmg").tempdir()?;
+                let path = dir.path().join("image.shadow");
+                if keep {
+                    let _dir = dir.keep();
+                }
+                Ok(Some(path))
+            }
+        }
+    }
+
+    /// Build the hdiutil command [`attach()`](#method.attach) would run, without running it.
+    ///
+    /// Useful to log or inspect the arguments. A [`shadow_temp()`](#method.shadow_temp) path is
+    /// picked anew each time, so it differs from the one used when attaching.
+    pub fn command(&self) -> io::Result<Command> {
+        self.validate()?;
+        Ok(self.build_command(self.shadow_path(false)?.as_deref()))
+    }
+
+    fn build_command(&self, shadow: Option<&Path>) -> Command {
+        let mut cmd = hdiutil();
         cmd.arg("attach");
 
         match self.mount {
@@ -201,42 +942,157 @@ impl Attach {
             }
         }
 
-        if self.force_readonly {
-            cmd.arg("-readonly");
+        match self.access {
+            Access::Default => {},
+            Access::ReadOnly => {
+                cmd.arg("-readonly");
+            },
+            Access::ReadWrite => {
+                cmd.arg("-readwrite");
+            }
         }
 
         if self.hidden {
             cmd.arg("-nobrowse");
         }
 
+        if self.no_verify {
+            cmd.arg("-noverify");
+        }
+
+        if self.no_autofsck {
+            cmd.arg("-noautofsck");
+        }
+
+        if self.no_idme {
+            cmd.arg("-noidme");
+        }
+
+        if self.not_removable {
+            cmd.arg("-notremovable");
+        }
+
+        if self.private {
+            cmd.arg("-private");
+        }
+
+        if let Some(path) = shadow {
+            cmd.arg("-shadow");
+            cmd.arg(path);
+        }
+
+        if let Some(ref path) = self.recover {
+            cmd.arg("-recover");
+            cmd.arg(path);
+        }
+
+        if let Some((offset, length)) = self.section {
+            cmd.arg("-section");
+            cmd.arg(format!("{},{}", offset, length));
+        }
+
+        if let Some(owners) = self.owners {
+            cmd.arg("-owners");
+            cmd.arg(if owners { "on" } else { "off" });
+        }
+
+        // Checked by validate()
+        if let Some(Ok(bytes)) = self.cache_size.map(|size| size.as_bytes()) {
+            cmd.arg("-cachesize");
+            cmd.arg(bytes.to_string());
+        }
+
+        for key in &self.drive_keys {
+            cmd.arg("-drivekey");
+            cmd.arg(key);
+        }
+
+        if let Some(ref library) = self.library {
+            cmd.arg("-library");
+            cmd.arg(library);
+        }
+
+        self.verbosity.add_args(&mut cmd);
+
+        match self.mount_policy {
+            MountPolicy::Default => {},
+            MountPolicy::Required => {
+                cmd.args(["-mount", "required"]);
+            },
+            MountPolicy::Optional => {
+                cmd.args(["-mount", "optional"]);
+            },
+            MountPolicy::Suppressed => {
+                cmd.args(["-mount", "suppressed"]);
+            }
+        }
+
+        if self.passphrase.is_some() {
+            cmd.arg("-stdinpass");
+        }
+
         cmd.arg("-plist");
+        cmd.args(&self.extra_args);
         cmd.arg(&self.image);
+        cmd
+    }
+
+    fn input(&self) -> Option<&[u8]> {
+        self.passphrase.as_ref().map(Passphrase::as_bytes)
+    }
 
-        info!("Attaching {:?}", cmd);
-        let output = cmd.output()?;
-        info!("Status {:?}", output.status);
-
-        if !output.status.success() {
-            // This is not as informative as I wish it would be
-            // .. but neither is hdiutil
-            return Err(io::Error::new(ErrorKind::Other, "hdiutil failed"));
-        }
-
-        if let Ok(plist) = Value::from_reader(Cursor::new(output.stdout)) {
-            let entities = check!(check!(check!(plist.as_dictionary()).get("system-entities")).as_array());
-            for entity in entities {
-                let properties = check!(entity.as_dictionary());
-                if let Some(mount_point) = properties.get("mount-point") {
-                    return Ok(Info {
-                        mount_point: PathBuf::from(check!(mount_point.as_string())),
-                        // If we don't have this something has gonne _really_ wrong
-                        device: PathBuf::from(check!(properties["dev-entry"].as_string())),
-                    });
+    /// Report any invalid options before running hdiutil.
+    fn validate(&self) -> io::Result<()> {
+        if self.source.is_some() && self.passphrase.is_some() {
+            return Err(DmgError::InvalidOptions("a passphrase cannot be read from stdin with the image").into());
+        }
+        if let Some(size) = self.cache_size {
+            size.as_bytes()?;
+        }
+        Ok(())
+    }
+
+    /// Run hdiutil, returning its plist output and the shadow file used.
+    fn attach_plist(&mut self) -> io::Result<(Value, Option<PathBuf>)> {
+        self.validate()?;
+        let shadow = self.shadow_path(true)?;
+        let mut cmd = self.build_command(shadow.as_deref());
+        let _locked = global_lock();
+        let output = match self.source.take() {
+            Some(source) => output_with_source(&mut cmd, source, self.timeout)?,
+            None => output_with_progress(&mut cmd, self.input(), None, self.timeout)?,
+        };
+        Ok((parse_plist(check_passphrase(output)?.stdout)?, shadow))
+    }
+
+    fn attach_volumes(mut self) -> io::Result<Volumes> {
+        let (plist, shadow) = self.attach_plist()?;
+        self.volumes_from(&plist, shadow, Some(self.mount_wait))
+    }
+
+    /// Parse and check the volumes hdiutil attached, detaching the image if a check fails.
+    ///
+    /// Waits up to `mount_wait` for the mount points to exist, unless it is `None`.
+    fn volumes_from(&self, plist: &Value, shadow: Option<PathBuf>, mount_wait: Option<Duration>)
+            -> io::Result<Volumes> {
+        detach_on_error(parse_volumes(plist)?, |mut volumes| {
+            if let MountPolicy::Required = self.mount_policy {
+                if let Some(entity) = volumes.entities.iter().find(|e| e.potentially_mountable && e.mount_point.is_none()) {
+                    return Err(DmgError::NotMounted(entity.device.clone()).into());
                 }
             }
-            return Err(io::Error::new(ErrorKind::Other, "could not extract data"));
-        }
-        return Err(io::Error::new(ErrorKind::InvalidData, "could not parse plist"));
+            for info in &mut volumes.volumes {
+                info.shadow = shadow.clone();
+            }
+            match mount_wait {
+                Some(wait) => wait_for_mounts(volumes, wait),
+                None => Ok(volumes),
+            }
+        })
+    }
+
+    fn attach_info(self) -> io::Result<Info> {
+        detach_on_error(self.attach_volumes()?, first_volume)
     }
 
     /// Attach the disk image
@@ -244,33 +1100,654 @@ impl Attach {
         self.attach_info().map(Handle)
     }
 
+    /// Attach the disk image, retrying up to `attempts` times `delay` apart while hdiutil fails
+    /// transiently.
+    ///
+    /// Disk Arbitration sometimes refuses attaching when many images are attached at once, with
+    /// errors like "Resource temporarily unavailable". Any other error is returned right away.
+    pub fn attach_with_retries(mut self, attempts: u32, delay: Duration) -> io::Result<Handle> {
+        let mut attempt = 1;
+        loop {
+            let streamed = self.source.is_some();
+            match self.attach_plist() {
+                Err(ref err) if attempt < attempts && !streamed && is_transient(err) => {
+                    info!("Attach failed, retrying: {}", err);
+                    thread::sleep(delay);
+                    attempt += 1;
+                },
+                result => {
+                    let (plist, shadow) = result?;
+                    let volumes = self.volumes_from(&plist, shadow, Some(self.mount_wait))?;
+                    return detach_on_error(volumes, first_volume).map(Handle);
+                }
+            }
+        }
+    }
+
     /// Attach the disk image, detaching when dropped
     pub fn with(self) -> io::Result<With> {
-        self.attach_info().map(With)
+        self.attach_info().map(|info| With(Some(info)))
+    }
+
+    /// Attach the disk image, returning the plist hdiutil printed as is.
+    ///
+    /// Useful to read properties [`Info`](struct.Info.html) does not have. Detach the image
+    /// yourself, for example with the `dev-entry` of the first of the `system-entities`.
+    pub fn attach_raw(mut self) -> io::Result<Value> {
+        self.attach_plist().map(|(plist, _)| plist)
+    }
+
+    /// Attach the disk image, returning every mounted volume
+    pub fn attach_all(self) -> io::Result<Volumes> {
+        self.attach_volumes()
+    }
+
+    /// Attach the disk image without blocking the current thread.
+    ///
+    /// Asynchronous version of [`attach()`](#method.attach).
+    #[cfg(feature = "async")]
+    pub async fn attach_async(self) -> io::Result<Handle> {
+        if self.source.is_some() {
+            return Err(DmgError::InvalidOptions("a streamed image cannot be attached asynchronously").into());
+        }
+        self.validate()?;
+        let shadow = self.shadow_path(true)?;
+        let output = output_with_input_async(self.build_command(shadow.as_deref()), self.input()).await?;
+        let plist = parse_plist(check_passphrase(output)?.stdout)?;
+        detach_on_error(self.volumes_from(&plist, shadow, None)?, first_volume).map(Handle)
     }
 }
 
-/// Detach an image using a path.
+/// Run `check` on the volumes of a just attached image, force detaching it if the check fails,
+/// since the caller never gets a handle to detach it with.
+pub(crate) fn detach_on_error<T, F>(volumes: Volumes, check: F) -> io::Result<T>
+        where F: FnOnce(Volumes) -> io::Result<T> {
+    detach_on_error_with(volumes, check, |device| detach(device, true))
+}
+
+fn detach_on_error_with<T, F, D>(volumes: Volumes, check: F, detach: D) -> io::Result<T>
+        where F: FnOnce(Volumes) -> io::Result<T>, D: FnOnce(&Path) -> io::Result<()> {
+    let device = volumes.device.clone();
+    check(volumes).inspect_err(|err| {
+        info!("Attached image failed a check, detaching: {}", err);
+        if let Err(err) = detach(&device) {
+            error!("Could not detach {:?}: {}", device, err);
+        }
+    })
+}
+
+/// Poll until every volume's mount point exists, giving up after `wait`.
+fn wait_for_mounts(volumes: Volumes, wait: Duration) -> io::Result<Volumes> {
+    let deadline = Instant::now() + wait;
+    for info in &volumes.volumes {
+        while !info.mount_point.exists() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DmgError::NotMounted(info.device.clone()).into());
+            }
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(10)));
+        }
+    }
+    Ok(volumes)
+}
+
+/// Whether hdiutil failed in a way that may go away when retried.
+pub(crate) fn is_transient(err: &io::Error) -> bool {
+    match DmgError::from_io_error(err) {
+        Some(DmgError::CommandFailed { stderr, .. }) => {
+            let stderr = stderr.to_lowercase();
+            stderr.contains("resource temporarily unavailable") || stderr.contains("resource busy")
+        },
+        _ => false,
+    }
+}
+
+fn first_volume(volumes: Volumes) -> io::Result<Info> {
+    match volumes.volumes.into_iter().next() {
+        Some(info) => Ok(info),
+        None => Err(DmgError::MissingProperty("mount-point").into()),
+    }
+}
+
+/// Mount the volumes of an image or device attached without mounting, returning the mount point.
 ///
-/// The path can be either a device node path or a mount point.
-pub fn detach<P: AsRef<Path>>(path: P, force: bool) -> io::Result<()> {
-    let mut cmd = Command::new(DISK_COMMAND);
+/// Attach without mounting with [`Attach::mount_suppressed()`](struct.Attach.html#method.mount_suppressed).
+///
+/// Use this to bring a volume online after, for example, checking its device node with fsck.
+pub fn mount_volume<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
+    let mut cmd = hdiutil();
+    cmd.arg("mount");
+    cmd.arg("-plist");
+    cmd.arg(path.as_ref());
+    let _locked = global_lock();
+    first_volume(parse_volumes(&run_plist(&mut cmd)?)?).map(|info| info.mount_point)
+}
+
+pub(crate) fn parse_encrypted(stdout: &str) -> Option<bool> {
+    stdout.lines().find_map(|line| match line.trim().strip_prefix("encrypted:")?.trim() {
+        "YES" => Some(true),
+        "NO" => Some(false),
+        _ => None,
+    })
+}
+
+/// Check whether an image is encrypted and needs a [`passphrase()`](struct.Attach.html#method.passphrase).
+pub fn is_encrypted<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut cmd = hdiutil();
+    cmd.arg("isencrypted");
+    cmd.arg(path.as_ref());
+
+    // hdiutil exits non-zero for some unencrypted images, so trust the output over the status
+    let output = output_with_input(&mut cmd, None)?;
+    match parse_encrypted(&String::from_utf8_lossy(&output.stdout)) {
+        Some(encrypted) => Ok(encrypted),
+        None => {
+            check_status(output)?;
+            Err(DmgError::UnexpectedOutput("isencrypted output").into())
+        }
+    }
+}
+
+/// Look up the BSD filesystem ID of a mounted volume, as printed by `hdiutil fsid`.
+///
+/// Useful with lower level APIs like `unmount(2)` after attaching an image.
+pub fn fsid<P: AsRef<Path>>(mount_point: P) -> io::Result<String> {
+    let mut cmd = hdiutil();
+    cmd.arg("fsid");
+    cmd.arg(mount_point.as_ref());
+    let output = run(&mut cmd)?;
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "" => Err(DmgError::UnexpectedOutput("fsid output").into()),
+        fsid => Ok(String::from(fsid)),
+    }
+}
+
+/// The parts of the plist printed by `hdiutil attach -plist` and `hdiutil mount -plist` this
+/// crate reads, also used for each image in `hdiutil info -plist`.
+#[derive(Deserialize)]
+struct AttachPlist {
+    #[serde(rename = "system-entities")]
+    system_entities: Option<Vec<SystemEntity>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SystemEntity {
+    // If this is missing something has gonne _really_ wrong
+    dev_entry: PathBuf,
+    mount_point: Option<PathBuf>,
+    volume_kind: Option<String>,
+    content_hint: Option<String>,
+    #[serde(default)]
+    potentially_mountable: bool,
+    #[serde(default)]
+    writable: bool,
+}
+
+pub(crate) fn parse_volumes(plist: &Value) -> io::Result<Volumes> {
+    let plist: AttachPlist = plist::from_value(plist).map_err(DmgError::PlistParse)?;
+    let entities = check!(plist.system_entities, "system-entities");
+    let mut volumes = Vec::new();
+    let mut all = Vec::new();
+    for entity in entities {
+        if let Some(ref mount_point) = entity.mount_point {
+            volumes.push(Info {
+                mount_point: mount_point.clone(),
+                device: entity.dev_entry.clone(),
+                filesystem: entity.volume_kind,
+                content_hint: entity.content_hint.clone(),
+                shadow: None,
+                writable: entity.writable,
+            });
+        }
+        all.push(Entity {
+            device: entity.dev_entry,
+            content_hint: entity.content_hint,
+            mount_point: entity.mount_point,
+            potentially_mountable: entity.potentially_mountable,
+        });
+    }
+    Ok(Volumes {
+        // The whole disk entity always comes first
+        device: check!(all.first(), "dev-entry").device.clone(),
+        volumes,
+        entities: all,
+    })
+}
+
+/// Run a command, turning a non-zero exit status into an error carrying its stderr.
+fn run(cmd: &mut Command) -> io::Result<Output> {
+    run_with_input(cmd, None)
+}
+
+/// Like [`run()`](fn.run.html), but first writes `input` to the command's stdin and closes it.
+fn run_with_input(cmd: &mut Command, input: Option<&[u8]>) -> io::Result<Output> {
+    check_status(output_with_input(cmd, input)?)
+}
+
+fn output_with_input(cmd: &mut Command, input: Option<&[u8]>) -> io::Result<Output> {
+    check_platform()?;
+    info!("Running {:?}", cmd);
+    let output = match input {
+        None => cmd.output().map_err(spawn_error)?,
+        Some(input) => {
+            cmd.stdin(Stdio::piped());
+            cmd.stdout(Stdio::piped());
+            cmd.stderr(Stdio::piped());
+            let mut child = cmd.spawn().map_err(spawn_error)?;
+            // Dropping stdin closes the pipe so hdiutil sees the end of the input
+            child.stdin.take().expect("stdin not piped").write_all(input).map_err(DmgError::Spawn)?;
+            child.wait_with_output().map_err(DmgError::Spawn)?
+        }
+    };
+    info!("Status {:?}", output.status);
+    Ok(output)
+}
+
+/// Parse a `-puppetstrings` progress line, updating the last seen `percent`.
+pub(crate) fn parse_progress(line: &str, percent: &mut f32) -> Option<Progress> {
+    if let Some(value) = line.strip_prefix("PERCENT:") {
+        *percent = value.trim().parse().ok()?;
+        Some(Progress { percent: *percent, message: None })
+    } else {
+        let message = line.strip_prefix("MESSAGE:")?;
+        Some(Progress { percent: *percent, message: Some(String::from(message.trim())) })
+    }
+}
+
+/// Like [`output_with_input()`](fn.output_with_input.html), but passes the `-puppetstrings`
+/// progress lines on stdout to `on_progress` as they arrive, and kills hdiutil if it runs for
+/// longer than `timeout`.
+fn output_with_progress(cmd: &mut Command, input: Option<&[u8]>, mut on_progress: Option<&mut ProgressFn>,
+        timeout: Option<Duration>) -> io::Result<Output> {
+    if on_progress.is_none() && timeout.is_none() {
+        return output_with_input(cmd, input);
+    }
+
+    check_platform()?;
+    info!("Running {:?}", cmd);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    cmd.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() });
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn().map_err(spawn_error)?;
+    if let Some(input) = input {
+        child.stdin.take().expect("stdin not piped").write_all(input).map_err(DmgError::Spawn)?;
+    }
+
+    // Drain stderr on the side so hdiutil never blocks on a full pipe
+    let stderr_reader = read_on_side(child.stderr.take().expect("stderr not piped"));
+    // Read stdout on the side too, so the deadline can be checked between lines
+    let (lines, received) = mpsc::channel();
+    let stdout_reader = BufReader::new(child.stdout.take().expect("stdout not piped"));
+    thread::spawn(move || {
+        for line in stdout_reader.lines() {
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stdout = Vec::new();
+    let mut percent = 0.0;
+    loop {
+        let line = match deadline {
+            None => match received.recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            },
+            Some(deadline) => match received.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => return Err(kill_timed_out(&mut child, timeout)),
+            },
+        };
+        let line = line.map_err(DmgError::Spawn)?;
+        match (parse_progress(&line, &mut percent), &mut on_progress) {
+            (Some(progress), Some(on_progress)) => on_progress(progress),
+            _ => {
+                stdout.extend_from_slice(line.as_bytes());
+                stdout.push(b'\n');
+            }
+        }
+    }
+
+    let status = wait_until(&mut child, deadline, timeout)?;
+    let stderr = stderr_reader.join().expect("stderr reader panicked").map_err(DmgError::Spawn)?;
+    info!("Status {:?}", status);
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Read all of a child's pipe on another thread, so the child never blocks on it filling up.
+fn read_on_side<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        pipe.read_to_end(&mut buffer).map(|_| buffer)
+    })
+}
+
+/// Run a command while copying `source` into its stdin, killing it if it runs for longer than
+/// `timeout`.
+///
+/// Writing and reading happen on separate threads, hdiutil may print before it has read all of
+/// its input.
+fn output_with_source(cmd: &mut Command, mut source: Box<dyn Read + Send>, timeout: Option<Duration>)
+        -> io::Result<Output> {
+    check_platform()?;
+    info!("Running {:?}", cmd);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn().map_err(spawn_error)?;
+    let mut stdin = child.stdin.take().expect("stdin not piped");
+    // Dropping stdin once the source is exhausted lets hdiutil see the end of the image
+    let writer = thread::spawn(move || io::copy(&mut source, &mut stdin).map(|_| ()));
+    let stdout_reader = read_on_side(child.stdout.take().expect("stdout not piped"));
+    let stderr_reader = read_on_side(child.stderr.take().expect("stderr not piped"));
+
+    let status = wait_until(&mut child, deadline, timeout)?;
+    let stdout = stdout_reader.join().expect("stdout reader panicked").map_err(DmgError::Spawn)?;
+    let stderr = stderr_reader.join().expect("stderr reader panicked").map_err(DmgError::Spawn)?;
+    match writer.join().expect("stdin writer panicked") {
+        // hdiutil is free to stop reading early, only a failing reader is an error
+        Err(err) if status.success() && err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+        _ => {},
+    }
+    info!("Status {:?}", status);
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Wait for `child` to exit, killing it if it is still running at `deadline`.
+fn wait_until(child: &mut Child, deadline: Option<Instant>, timeout: Option<Duration>) -> io::Result<ExitStatus> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(child.wait().map_err(DmgError::Spawn)?),
+    };
+    loop {
+        if let Some(status) = child.try_wait().map_err(DmgError::Spawn)? {
+            return Ok(status);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(kill_timed_out(child, timeout));
+        }
+        thread::sleep(cmp::min(deadline - now, Duration::from_millis(10)));
+    }
+}
+
+fn kill_timed_out(child: &mut Child, timeout: Option<Duration>) -> io::Error {
+    let timeout = timeout.expect("timed out without a timeout");
+    warn!("hdiutil did not finish within {:?}, killing it", timeout);
+    // Killing fails if hdiutil exited just now, either way it has to be reaped
+    let _ = child.kill();
+    let _ = child.wait();
+    DmgError::TimedOut(timeout).into()
+}
+
+/// Call `on_progress` for every `-puppetstrings` line in already collected output.
+#[cfg(feature = "async")]
+fn replay_progress(stdout: &[u8], on_progress: Option<&mut ProgressFn>) {
+    if let Some(on_progress) = on_progress {
+        let mut percent = 0.0;
+        for line in String::from_utf8_lossy(stdout).lines() {
+            if let Some(progress) = parse_progress(line, &mut percent) {
+                on_progress(progress);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+async fn output_with_input_async(cmd: Command, input: Option<&[u8]>) -> io::Result<Output> {
+    use tokio::io::AsyncWriteExt;
+
+    check_platform()?;
+    info!("Running {:?}", cmd);
+    let mut cmd = tokio::process::Command::from(cmd);
+    let output = match input {
+        None => cmd.output().await.map_err(spawn_error)?,
+        Some(input) => {
+            cmd.stdin(Stdio::piped());
+            cmd.stdout(Stdio::piped());
+            cmd.stderr(Stdio::piped());
+            let mut child = cmd.spawn().map_err(spawn_error)?;
+            // Dropping stdin closes the pipe so hdiutil sees the end of the input
+            let mut stdin = child.stdin.take().expect("stdin not piped");
+            stdin.write_all(input).await.map_err(DmgError::Spawn)?;
+            drop(stdin);
+            child.wait_with_output().await.map_err(DmgError::Spawn)?
+        }
+    };
+    info!("Status {:?}", output.status);
+    Ok(output)
+}
+
+/// Turn a non-zero exit status into an error, otherwise log any warnings hdiutil printed.
+fn check_status(output: Output) -> io::Result<Output> {
+    if !output.status.success() {
+        return Err(DmgError::CommandFailed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }.into());
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines().filter(|line| !line.trim().is_empty()) {
+        warn!("hdiutil: {}", line);
+    }
+    Ok(output)
+}
+
+/// Like [`check_status()`](fn.check_status.html), but reports a rejected passphrase as
+/// `DmgError::IncorrectPassphrase`.
+fn check_passphrase(output: Output) -> io::Result<Output> {
+    if !output.status.success() && String::from_utf8_lossy(&output.stderr).contains("Authentication error") {
+        return Err(DmgError::IncorrectPassphrase.into());
+    }
+    check_status(output)
+}
+
+fn parse_plist(stdout: Vec<u8>) -> io::Result<Value> {
+    Value::from_reader(Cursor::new(stdout))
+        .map_err(|err| DmgError::PlistParse(err).into())
+}
+
+/// Run a command producing a plist on stdout and parse it.
+fn run_plist(cmd: &mut Command) -> io::Result<Value> {
+    parse_plist(run(cmd)?.stdout)
+}
+
+fn detach_command(verb: &str, path: &Path, force: bool, verbosity: Verbosity) -> Command {
+    let mut cmd = hdiutil();
+    cmd.stdin(Stdio::null());
     cmd.stdout(Stdio::null());
-    cmd.stderr(Stdio::null());
+    cmd.stderr(Stdio::piped());
 
-    cmd.arg("detach");
+    cmd.arg(verb);
     if force {
         cmd.arg("-force");
     }
-    cmd.arg(path.as_ref());
+    verbosity.add_args(&mut cmd);
+    cmd.arg(path);
+    cmd
+}
 
-    info!("Detaching (force: {:?}): {:?}", force, cmd);
-    let status = cmd.status()?;
-    info!("Status {:?}", status);
+/// Run a detach-like command, reading its stderr on the side in case it fails.
+fn run_detach(mut cmd: Command, path: &Path, timeout: Option<Duration>) -> io::Result<()> {
+    check_platform()?;
+    let _locked = global_lock();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut child = cmd.spawn().map_err(spawn_error)?;
+    let stderr_reader = read_on_side(child.stderr.take().expect("stderr not piped"));
+    let status = wait_until(&mut child, deadline, timeout)?;
+    let stderr = stderr_reader.join().expect("stderr reader panicked").map_err(DmgError::Spawn)?;
+    detach_status(status, &stderr, path)
+}
 
+fn detach_status(status: ExitStatus, stderr: &[u8], path: &Path) -> io::Result<()> {
+    info!("Status {:?}", status);
+    let stderr = String::from_utf8_lossy(stderr);
     if status.success() {
-        Ok(())
-    } else {
-        Err(io::Error::new(ErrorKind::Other, "non-zero exit status for detach"))
+        for line in stderr.lines().filter(|line| !line.trim().is_empty()) {
+            warn!("hdiutil: {}", line);
+        }
+        return Ok(());
+    }
+    let message = stderr.to_lowercase();
+    if message.contains("no such disk") || message.contains("no such file or directory") {
+        return Err(DmgError::NotAttached(path.to_path_buf()).into());
+    }
+    Err(DmgError::CommandFailed { status, stderr: stderr.into_owned() }.into())
+}
+
+/// Builder to detach an image, for more control than [`detach()`](fn.detach.html).
+#[must_use = "builders do nothing unless `detach()` is called"]
+pub struct Detach {
+    path: PathBuf,
+    force: bool,
+    verbosity: Verbosity,
+    timeout: Option<Duration>,
+}
+
+impl Detach {
+    /// Creates a new builder detaching the image at `path`, a device node or a mount point.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Detach {
+        Detach {
+            path: path.into(),
+            force: false,
+            verbosity: Verbosity::Default,
+            timeout: None,
+        }
+    }
+
+    /// Detach even if files on the image are still open.
+    pub fn force(mut self) -> Detach {
+        self.force = true;
+        self
+    }
+
+    /// Ask hdiutil to print as little as possible.
+    pub fn quiet(mut self) -> Detach {
+        self.verbosity = Verbosity::Quiet;
+        self
+    }
+
+    /// Ask hdiutil to print extra diagnostics.
+    pub fn verbose(mut self) -> Detach {
+        self.verbosity = Verbosity::Verbose;
+        self
+    }
+
+    /// Kill hdiutil and fail with `DmgError::TimedOut` if detaching takes longer than `timeout`.
+    ///
+    /// Only applies to [`detach()`](#method.detach).
+    pub fn timeout(mut self, timeout: Duration) -> Detach {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Detach the image.
+    pub fn detach(self) -> io::Result<()> {
+        let cmd = detach_command("detach", &self.path, self.force, self.verbosity);
+        info!("Detaching (force: {:?}): {:?}", self.force, cmd);
+        run_detach(cmd, &self.path, self.timeout)
+    }
+
+    /// Detach the image without blocking the current thread.
+    #[cfg(feature = "async")]
+    pub async fn detach_async(self) -> io::Result<()> {
+        let cmd = detach_command("detach", &self.path, self.force, self.verbosity);
+        check_platform()?;
+        info!("Detaching (force: {:?}): {:?}", self.force, cmd);
+        let output = tokio::process::Command::from(cmd).output().await.map_err(spawn_error)?;
+        detach_status(output.status, &output.stderr, &self.path)
     }
 }
+
+/// Detach an image using a path.
+///
+/// The path can be either a device node path or a mount point.
+pub fn detach<P: AsRef<Path>>(path: P, force: bool) -> io::Result<()> {
+    Detach { force, ..Detach::new(path.as_ref()) }.detach()
+}
+
+/// Eject an image using a path.
+///
+/// Like [`detach()`](fn.detach.html), but goes through Disk Arbitration's eject which flushes
+/// and notifies other applications first.
+pub fn eject<P: AsRef<Path>>(path: P, force: bool) -> io::Result<()> {
+    let cmd = detach_command("eject", path.as_ref(), force, Verbosity::Default);
+    info!("Ejecting (force: {:?}): {:?}", force, cmd);
+    run_detach(cmd, path.as_ref(), None)
+}
+
+/// Unmount a single volume using its mount point, leaving the image attached.
+///
+/// Unlike [`detach()`](fn.detach.html) other volumes of the image stay mounted, and the volume
+/// can be mounted again with [`mount_volume()`](fn.mount_volume.html).
+pub fn unmount<P: AsRef<Path>>(mount_point: P, force: bool) -> io::Result<()> {
+    let cmd = detach_command("unmount", mount_point.as_ref(), force, Verbosity::Default);
+    info!("Unmounting (force: {:?}): {:?}", force, cmd);
+    run_detach(cmd, mount_point.as_ref(), None)
+}
+
+pub(crate) fn parse_attached(plist: &Value) -> io::Result<Vec<AttachedImage>> {
+    let images = check!(check!(plist.as_dictionary(), "images"), "images", as_array);
+    let mut attached = Vec::new();
+    for image in images {
+        let image_path = PathBuf::from(check!(check!(image.as_dictionary(), "images"), "image-path", as_string));
+        let volumes = parse_volumes(image)?;
+        attached.push(AttachedImage {
+            image_path,
+            device: volumes.device,
+            entities: volumes.entities,
+        });
+    }
+    Ok(attached)
+}
+
+pub(crate) fn parse_device_info(plist: &Value, device: &Path) -> io::Result<Info> {
+    let images = check!(check!(plist.as_dictionary(), "images"), "images", as_array);
+    for image in images {
+        let volumes = parse_volumes(image)?;
+        if let Some(info) = volumes.volumes.into_iter().find(|info| info.device == device) {
+            return Ok(info);
+        }
+    }
+    Err(DmgError::NotMounted(device.to_path_buf()).into())
+}
+
+fn run_info() -> io::Result<Value> {
+    let mut cmd = hdiutil();
+    cmd.arg("info");
+    cmd.arg("-plist");
+    run_plist(&mut cmd)
+}
+
+/// List every disk image currently attached to the system.
+///
+/// Useful to clean up images left attached by an earlier run.
+pub fn attached_images() -> io::Result<Vec<AttachedImage>> {
+    parse_attached(&run_info()?)
+}
+
+/// Detach every attachment of the image file at `image`.
+///
+/// Unlike [`detach()`](fn.detach.html) with a mount point, this detaches the whole disk device,
+/// so no device nodes are left behind. Does nothing if the image is not attached.
+pub fn detach_image<P: AsRef<Path>>(image: P, force: bool) -> io::Result<()> {
+    // hdiutil reports images by their absolute path
+    let image = image.as_ref();
+    let image = image.canonicalize().unwrap_or_else(|_| image.to_path_buf());
+    for attached in attached_images()?.into_iter().filter(|attached| attached.image_path == image) {
+        detach(attached.device, force)?;
+    }
+    Ok(())
+}
+
+/// Detach an image using a path without blocking the current thread.
+///
+/// Asynchronous version of [`detach()`](fn.detach.html).
+#[cfg(feature = "async")]
+pub async fn detach_async<P: AsRef<Path>>(path: P, force: bool) -> io::Result<()> {
+    Detach { force, ..Detach::new(path.as_ref()) }.detach_async().await
+}
@@ -6,8 +6,12 @@
 // copied, modified, or distributed except according to those terms.
 
 use std::fs::File;
+use std::io::ErrorKind;
 
 use super::*;
+use super::create::{BlankImage, Filesystem, MakeHybrid, Size, VolumeName};
+use super::convert;
+use super::image::{self, ResizeLimits};
 
 static SAMPLE_IMAGE_PATH: &str = "Test.dmg";
 static SAMPLE_FILE_NAME: &str = "SAMPLE";
@@ -48,3 +52,782 @@ fn force_readonly() {
         .expect_err("create should fail");
     assert_eq!(err.raw_os_error(), Some(ERRRNO_EROFS));
 }
+
+#[test]
+fn create_blank() {
+    logger!();
+    let image = BlankImage::new(1024 * 1024)
+        .volume_name("Blank")
+        .create_temp()
+        .expect("error creating");
+    let info = Attach::new(&*image)
+        .mount_temp()
+        .hidden()
+        .with()
+        .expect("error attaching");
+    assert!(info.mount_point.exists());
+}
+
+#[test]
+fn size_args() {
+    assert_eq!(Size::from(4096).size_arg(), "4096");
+    assert_eq!(Size::sectors(8).size_arg(), "8b");
+    assert_eq!(Size::mib(200).size_arg(), "200m");
+    assert_eq!(Size::gib(2).as_bytes().unwrap(), 2 * 1024 * 1024 * 1024);
+    assert!(matches!(Size::gib(u64::MAX / 1024).as_bytes(), Err(DmgError::SizeOverflow(_))));
+    assert_eq!(Size::mib(1), Size::bytes(1 << 20));
+    assert_eq!(Size::sectors(2048), Size::mib(1));
+    assert_ne!(Size::mib(1), Size::sectors(2047));
+    assert_ne!(Size::gib(u64::MAX), Size::mib(u64::MAX));
+    let err = Attach::new("Test.dmg").cache_size(Size::sectors(u64::MAX)).command().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = BlankImage::new(1).band_size(Size::mib(u64::MAX)).command("Blank").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn resize_limits() {
+    let limits = image::parse_limits("71680\t409600\t34359738368\n").unwrap();
+    assert_eq!(limits, ResizeLimits {
+        min_sectors: 71680,
+        current_sectors: 409600,
+        max_sectors: 34359738368,
+    });
+    assert_eq!(image::parse_limits("garbage\n"), None);
+}
+
+#[test]
+fn compact_reclaimed() {
+    assert_eq!(image::parse_reclaimed("Reclaimed 0 bytes out of 1023.6 MB possible.\n"), Some(0));
+    assert_eq!(image::parse_reclaimed("Starting to compact…\nReclaimed 1.5 GB out of 3 GB possible.\n"),
+               Some(3 << 29));
+    assert_eq!(image::parse_reclaimed("Finishing compaction…\n"), None);
+}
+
+static IMAGE_INFO_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Checksum Type</key>
+    <string>CRC32</string>
+    <key>Format</key>
+    <string>UDZO</string>
+    <key>Properties</key>
+    <dict>
+        <key>Encrypted</key>
+        <false/>
+    </dict>
+    <key>Size Information</key>
+    <dict>
+        <key>Total Bytes</key>
+        <integer>1048576</integer>
+    </dict>
+    <key>partitions</key>
+    <dict>
+        <key>block-size</key>
+        <integer>512</integer>
+        <key>partition-scheme</key>
+        <string>GUID</string>
+        <key>partitions</key>
+        <array>
+            <dict>
+                <key>partition-hint</key>
+                <string>MBR</string>
+                <key>partition-length</key>
+                <integer>1</integer>
+                <key>partition-number</key>
+                <integer>0</integer>
+                <key>partition-synthesized</key>
+                <true/>
+            </dict>
+            <dict>
+                <key>partition-filesystems</key>
+                <dict>
+                    <key>HFS+</key>
+                    <string>Test</string>
+                </dict>
+                <key>partition-hint</key>
+                <string>Apple_HFS</string>
+                <key>partition-length</key>
+                <integer>2008</integer>
+                <key>partition-number</key>
+                <integer>1</integer>
+            </dict>
+        </array>
+    </dict>
+</dict>
+</plist>"#;
+
+#[test]
+fn image_info() {
+    let plist = Value::from_reader(Cursor::new(IMAGE_INFO_PLIST)).unwrap();
+    let info = image::parse_image_info(&plist).expect("error parsing");
+    assert_eq!(info.format, "UDZO");
+    assert_eq!(info.size_bytes, 1048576);
+    assert_eq!(info.checksum_type.as_deref(), Some("CRC32"));
+    assert_eq!(info.partition_scheme.as_deref(), Some("GUID"));
+    assert!(!info.encrypted);
+}
+
+#[test]
+fn image_volumes() {
+    let plist = Value::from_reader(Cursor::new(IMAGE_INFO_PLIST)).unwrap();
+    assert_eq!(image::parse_volumes(&plist).expect("error parsing"), [image::VolumeInfo {
+        partition: 1,
+        name: String::from("Test"),
+        filesystem: String::from("HFS+"),
+        hint: Some(String::from("Apple_HFS")),
+        size_bytes: 2008 * 512,
+    }]);
+}
+
+static ATTACH_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>system-entities</key>
+    <array>
+        <dict>
+            <key>content-hint</key>
+            <string>GUID_partition_scheme</string>
+            <key>dev-entry</key>
+            <string>/dev/disk4</string>
+            <key>potentially-mountable</key>
+            <false/>
+            <key>unmapped-content-hint</key>
+            <string>GUID_partition_scheme</string>
+        </dict>
+        <dict>
+            <key>content-hint</key>
+            <string>Apple_HFS</string>
+            <key>dev-entry</key>
+            <string>/dev/disk4s1</string>
+            <key>mount-point</key>
+            <string>/Volumes/Test</string>
+            <key>potentially-mountable</key>
+            <true/>
+            <key>unmapped-content-hint</key>
+            <string>48465300-0000-11AA-AA11-00306543ECAC</string>
+            <key>volume-kind</key>
+            <string>hfs</string>
+            <key>writable</key>
+            <true/>
+        </dict>
+        <dict>
+            <key>content-hint</key>
+            <string>ISO9660</string>
+            <key>dev-entry</key>
+            <string>/dev/disk4s2</string>
+            <key>mount-point</key>
+            <string>/Volumes/Test 1</string>
+            <key>potentially-mountable</key>
+            <true/>
+            <key>unmapped-content-hint</key>
+            <string>ISO9660</string>
+            <key>volume-kind</key>
+            <string>cd9660</string>
+            <key>writable</key>
+            <false/>
+        </dict>
+    </array>
+</dict>
+</plist>"#;
+
+fn attach_plist() -> Value {
+    Value::from_reader(Cursor::new(ATTACH_PLIST)).unwrap()
+}
+
+#[test]
+fn parse_all_volumes() {
+    let volumes = parse_volumes(&attach_plist()).expect("error parsing");
+    assert_eq!(volumes.device, Path::new("/dev/disk4"));
+    let mount_points: Vec<_> = volumes.volumes.iter().map(|info| info.mount_point.as_path()).collect();
+    assert_eq!(mount_points, [Path::new("/Volumes/Test"), Path::new("/Volumes/Test 1")]);
+    assert_eq!(volumes.volumes[1].device, Path::new("/dev/disk4s2"));
+    assert!(volumes.volumes[0].writable);
+    assert!(!volumes.volumes[1].writable);
+}
+
+#[test]
+fn parse_entities() {
+    let entities = parse_volumes(&attach_plist()).expect("error parsing").entities;
+    assert_eq!(entities.len(), 3);
+    assert_eq!(entities[0], Entity {
+        device: PathBuf::from("/dev/disk4"),
+        content_hint: Some(String::from("GUID_partition_scheme")),
+        mount_point: None,
+        potentially_mountable: false,
+    });
+    assert_eq!(entities[2].mount_point.as_deref(), Some(Path::new("/Volumes/Test 1")));
+}
+
+#[test]
+fn parse_volume_kind() {
+    let info = &parse_volumes(&attach_plist()).expect("error parsing").volumes[0];
+    assert_eq!(info.filesystem.as_deref(), Some("hfs"));
+    assert_eq!(info.content_hint.as_deref(), Some("Apple_HFS"));
+}
+
+#[test]
+fn encrypted_output() {
+    assert_eq!(parse_encrypted("encrypted: YES\nblocksize: 4096\n"), Some(true));
+    assert_eq!(parse_encrypted("encrypted: NO\n"), Some(false));
+    assert_eq!(parse_encrypted("hdiutil: isencrypted failed\n"), None);
+}
+
+#[test]
+fn missing_property() {
+    let err = parse_volumes(&Value::Dictionary(Default::default())).expect_err("parse should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::MissingProperty("system-entities"))));
+}
+
+#[test]
+fn malformed_entity() {
+    let plist = attach_plist();
+    let mut plist = plist.into_dictionary().unwrap();
+    let entities = plist.get_mut("system-entities").and_then(Value::as_array_mut).unwrap();
+    entities[1].as_dictionary_mut().unwrap().remove("dev-entry");
+    let err = parse_volumes(&Value::Dictionary(plist)).expect_err("parse should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+#[cfg(not(target_os = "macos"))]
+fn unsupported_platform() {
+    let err = detach("/Volumes/Test", false).expect_err("detach should fail");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::Unsupported)));
+}
+
+#[test]
+fn hdiutil_not_found() {
+    let message = DmgError::HdiutilNotFound(None).to_string();
+    assert!(message.contains("macOS") && message.contains("DMG_HDIUTIL"));
+    let message = DmgError::HdiutilNotFound(Some(PathBuf::from("/opt/mock-hdiutil"))).to_string();
+    assert!(message.contains("\"/opt/mock-hdiutil\"") && message.contains("DMG_HDIUTIL"));
+}
+
+#[test]
+fn progress_lines() {
+    let mut percent = 0.0;
+    assert_eq!(parse_progress("MESSAGE:Preparing imaging engine…", &mut percent),
+               Some(Progress { percent: 0.0, message: Some(String::from("Preparing imaging engine…")) }));
+    assert_eq!(parse_progress("PERCENT:42.500000", &mut percent),
+               Some(Progress { percent: 42.5, message: None }));
+    assert_eq!(parse_progress("MESSAGE:Finishing…", &mut percent).map(|p| p.percent), Some(42.5));
+    assert_eq!(parse_progress("created: /tmp/Test.dmg", &mut percent), None);
+}
+
+#[test]
+fn segment_listing() {
+    let dir = tempfile::tempdir().expect("error creating directory");
+    for name in &["Big.dmg", "Big.002.dmgpart", "Big.003.dmgpart", "Other.002.dmgpart"] {
+        File::create(dir.path().join(name)).expect("error creating file");
+    }
+    let segments = convert::segment_paths(dir.path().join("Big.dmg")).expect("error listing");
+    assert_eq!(segments, [
+        dir.path().join("Big.dmg"),
+        dir.path().join("Big.002.dmgpart"),
+        dir.path().join("Big.003.dmgpart"),
+    ]);
+}
+
+#[test]
+fn checksum_output() {
+    let stdout = "Reading Protective Master Boot Record (MBR : 0)…\n\
+                  ..............................................................\n\
+                  calculated CRC32 checksum = $2AD2BE59\n";
+    assert_eq!(image::parse_checksum(stdout).as_deref(), Some("2AD2BE59"));
+    assert_eq!(image::parse_checksum("hdiutil: checksum failed\n"), None);
+}
+
+#[test]
+fn volume_names() {
+    assert!(VolumeName::new("Install Something").is_ok());
+    assert!(matches!(VolumeName::new(""), Err(DmgError::InvalidVolumeName { .. })));
+    assert!(matches!(VolumeName::new("Bad\0Name"), Err(DmgError::InvalidVolumeName { .. })));
+    assert!(VolumeName::new("x".repeat(256)).is_err());
+    // Astral plane characters take two UTF-16 code units each
+    assert!(VolumeName::new("\u{1F4BE}".repeat(127)).is_ok());
+    assert!(VolumeName::new("\u{1F4BE}".repeat(128)).is_err());
+    assert!(VolumeName::new("\u{e9}".repeat(255)).is_ok());
+
+    let err = BlankImage::new(Size::mib(1))
+        .filesystem(Filesystem::FAT32)
+        .volume_name("TOO LONG FOR FAT")
+        .create("Unused.dmg")
+        .expect_err("create should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // Six characters, but twelve bytes: too long for FAT32, fine for exFAT counting UTF-16
+    let err = BlankImage::new(Size::mib(1))
+        .filesystem(Filesystem::FAT32)
+        .volume_name("\u{e9}".repeat(6))
+        .create("Unused.dmg")
+        .expect_err("create should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(BlankImage::new(Size::mib(1)).filesystem(Filesystem::ExFAT).volume_name("\u{e9}".repeat(6))
+        .command("Unused.dmg").is_ok());
+    let err = BlankImage::new(Size::mib(1))
+        .filesystem(Filesystem::ExFAT)
+        .volume_name("\u{e9}".repeat(12))
+        .create("Unused.dmg")
+        .expect_err("create should fail");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let err = MakeHybrid::new("Unused").volume_name("").create("Unused.iso").expect_err("create should fail");
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidVolumeName { .. })));
+
+    // ISO9660 allows 32 bytes and Joliet 16 UTF-16 code units, both used when no flags are set
+    for hybrid in [MakeHybrid::new("Unused").iso(), MakeHybrid::new("Unused")] {
+        let err = hybrid.volume_name("x".repeat(33)).create("Unused.iso").expect_err("create should fail");
+        assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidVolumeName { .. })));
+    }
+    for hybrid in [MakeHybrid::new("Unused").joliet(), MakeHybrid::new("Unused")] {
+        let err = hybrid.volume_name("x".repeat(17)).create("Unused.iso").expect_err("create should fail");
+        assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidVolumeName { .. })));
+    }
+    assert!(MakeHybrid::new("Unused").iso().volume_name("x".repeat(17)).validate().is_ok());
+    assert!(MakeHybrid::new("Unused").hfs().udf().volume_name("x".repeat(33)).validate().is_ok());
+}
+
+#[test]
+fn band_sizes() {
+    for size in [Size::bytes(1000), Size::sectors(3)] {
+        let err = BlankImage::new(Size::mib(1)).band_size(size).create("Unused.sparsebundle").unwrap_err();
+        assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidBandSize(_))));
+    }
+}
+
+const INFO_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>framework</key>
+    <string>671.100.2</string>
+    <key>images</key>
+    <array>
+        <dict>
+            <key>image-path</key>
+            <string>/Users/test/Other.dmg</string>
+            <key>system-entities</key>
+            <array>
+                <dict>
+                    <key>content-hint</key>
+                    <string>GUID_partition_scheme</string>
+                    <key>dev-entry</key>
+                    <string>/dev/disk3</string>
+                </dict>
+            </array>
+        </dict>
+        <dict>
+            <key>image-path</key>
+            <string>/Users/test/Test.dmg</string>
+            <key>system-entities</key>
+            <array>
+                <dict>
+                    <key>content-hint</key>
+                    <string>GUID_partition_scheme</string>
+                    <key>dev-entry</key>
+                    <string>/dev/disk4</string>
+                </dict>
+                <dict>
+                    <key>content-hint</key>
+                    <string>Apple_HFS</string>
+                    <key>dev-entry</key>
+                    <string>/dev/disk4s1</string>
+                    <key>mount-point</key>
+                    <string>/Volumes/Test</string>
+                </dict>
+            </array>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#;
+
+fn info_plist() -> Value {
+    Value::from_reader(Cursor::new(INFO_PLIST)).unwrap()
+}
+
+#[test]
+fn attached_image_list() {
+    let images = parse_attached(&info_plist()).unwrap();
+    assert_eq!(images.len(), 2);
+    assert_eq!(images[0].image_path, Path::new("/Users/test/Other.dmg"));
+    assert_eq!(images[0].device, Path::new("/dev/disk3"));
+    assert_eq!(images[1].device, Path::new("/dev/disk4"));
+    let mount_points: Vec<_> = images[1].entities.iter().map(|entity| entity.mount_point.as_deref()).collect();
+    assert_eq!(mount_points, [None, Some(Path::new("/Volumes/Test"))]);
+}
+
+#[test]
+fn device_info() {
+    let info = parse_device_info(&info_plist(), Path::new("/dev/disk4s1")).unwrap();
+    assert_eq!(info.mount_point, Path::new("/Volumes/Test"));
+    assert_eq!(info.content_hint.as_deref(), Some("Apple_HFS"));
+    let err = parse_device_info(&info_plist(), Path::new("/dev/disk4")).unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::NotMounted(_))));
+}
+
+#[test]
+fn temp_image_paths() {
+    let dir = tempfile::tempdir().expect("error creating directory");
+    let image = create::TempImagePath::new_in(dir.path(), ".sparseimage").unwrap();
+    assert!(image.is_file());
+    assert_eq!(image.extension().unwrap(), "sparseimage");
+
+    let bundle = create::TempImagePath::new_in(dir.path(), ".sparsebundle").unwrap();
+    let parent = bundle.parent().unwrap().to_path_buf();
+    assert!(!bundle.exists());
+    assert!(parent.is_dir());
+    drop(bundle);
+    assert!(!parent.exists());
+
+    let bundle = create::TempImagePath::with_extension(create::FolderImageFormat::UDSB.extension()).unwrap();
+    assert_eq!(bundle.extension().unwrap(), "sparsebundle");
+
+    let image = create::TempImagePath::new_in(dir.path(), ".dmg").unwrap().persist().unwrap();
+    assert!(image.is_file());
+
+    let image = create::TempImagePath::new_in(dir.path(), ".dmg").unwrap();
+    assert!(image.check_created(&image).is_ok());
+    let stray = dir.path().join("Stray.dmg");
+    File::create(&stray).unwrap();
+    let err = image.check_created(&stray).unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::UnexpectedOutput(_))));
+    assert!(!stray.exists());
+
+    let err = BlankImage::new(Size::mib(1)).create_temp_in(dir.path(), ".img").unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidOptions(_))));
+}
+
+#[test]
+fn format_names() {
+    assert_eq!("UDZO".parse::<create::FolderImageFormat>().unwrap(), create::FolderImageFormat::UDZO);
+    assert_eq!(create::FolderImageFormat::UDSB.to_string(), "UDSB");
+    assert!(matches!("zip".parse::<create::FolderImageFormat>(), Err(DmgError::UnknownFormat(_))));
+    assert!("UNIV".parse::<convert::ConvertFormat>().is_err());
+}
+
+#[test]
+fn wait_timeout() {
+    let timeout = Duration::from_millis(50);
+    let mut child = Command::new("sleep").arg("5").spawn().expect("error running sleep");
+    let err = wait_until(&mut child, Some(Instant::now() + timeout), Some(timeout)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(child.try_wait().unwrap().is_some());
+}
+
+const PMAP_OUTPUT: &str = "\
+Partition List:
+## Dev________________ Type____________________ Name____________________ Start___ Size____ End_____
+ -1                    MBR                                                     0        1        0
+  0 disk4s1            Primary GPT Header                                      1        1        1
+  1 disk4s2            Apple_HFS                disk image                    40   204720   204759
+  2                    Backup GPT Header                                  204799        1   204799
+
+Legend
+";
+
+#[test]
+fn partition_map() {
+    let partitions = image::parse_partition_map(PMAP_OUTPUT).unwrap();
+    assert_eq!(partitions.len(), 4);
+    assert_eq!(partitions[0].index, -1);
+    assert_eq!(partitions[0].partition_type, "MBR");
+    assert_eq!(partitions[1].partition_type, "Primary GPT Header");
+    assert_eq!(partitions[2], image::Partition {
+        index: 1,
+        partition_type: String::from("Apple_HFS"),
+        name: String::from("disk image"),
+        start_sector: 40,
+        length_sectors: 204720,
+    });
+    assert_eq!(partitions[3].start_sector, 204799);
+    assert!(image::parse_partition_map("no table").is_none());
+}
+
+#[test]
+fn internet_enabled_output() {
+    assert_eq!(image::parse_internet_enabled("internet-enable: YES\n"), Some(true));
+    assert_eq!(image::parse_internet_enabled("internet-enable: NO\n"), Some(false));
+    assert_eq!(image::parse_internet_enabled("hdiutil: internet-enable failed\n"), None);
+}
+
+#[test]
+fn created_output() {
+    let stdout = b"PERCENT:100\ncreated: /Users/test/Image.dmg\n";
+    assert_eq!(create::parse_created(stdout), Some(PathBuf::from("/Users/test/Image.dmg")));
+    assert_eq!(create::parse_created(b"\n"), None);
+}
+
+#[test]
+fn create_failures() {
+    use std::os::unix::process::ExitStatusExt;
+    let output = |code: i32, stdout: &[u8], stderr: &[u8]| Output {
+        status: ExitStatus::from_raw(code << 8),
+        stdout: stdout.to_vec(),
+        stderr: stderr.to_vec(),
+    };
+    let created = create::created_path(output(0, b"created: /tmp/Image.dmg\n", b""), PathBuf::from("Image.dmg"));
+    assert_eq!(created.unwrap(), Path::new("/tmp/Image.dmg"));
+
+    let stderr = b"hdiutil: create failed - File exists\n";
+    let err = create::created_path(output(1, b"", stderr), PathBuf::from("Image.dmg")).unwrap_err();
+    let err = DmgError::from_io_error(&err).expect("not a DmgError");
+    assert!(matches!(err, DmgError::CommandFailed { stderr, .. } if stderr.contains("File exists")));
+    assert_eq!(err.exit_code(), Some(1));
+}
+
+#[test]
+fn image_extensions() {
+    let blank = BlankImage::new(Size::mib(1)).image_type(create::ImageType::SparseBundle);
+    assert_eq!(blank.extension(), "sparsebundle");
+    assert_eq!(create::path_with_extension(Path::new("Image"), "dmg"), Path::new("Image.dmg"));
+    assert_eq!(create::path_with_extension(Path::new("Image.dmg"), "dmg"), Path::new("Image.dmg"));
+    assert_eq!(create::path_with_extension(Path::new("Image.v1"), "dmg"), Path::new("Image.v1.dmg"));
+}
+
+#[test]
+fn verify_failures() {
+    use image::{parse_verify_failure, VerifyFailure};
+    let checksum = "hdiutil: verify: checksum of \"Test.dmg\" is INVALID\nhdiutil: verify failed - corrupt image\n";
+    assert_eq!(parse_verify_failure(checksum), VerifyFailure::ChecksumMismatch);
+    assert_eq!(parse_verify_failure("hdiutil: verify failed - Input/output error\n"), VerifyFailure::Io);
+    assert_eq!(parse_verify_failure("hdiutil: verify failed - image not recognized\n"), VerifyFailure::Format);
+    assert_eq!(parse_verify_failure("hdiutil: verify failed - Resource busy\n"), VerifyFailure::Other);
+}
+
+#[test]
+fn detach_errors() {
+    use std::os::unix::process::ExitStatusExt;
+    let failed = ExitStatus::from_raw(1 << 8);
+    let path = Path::new("/dev/disk9");
+    let err = detach_status(failed, b"hdiutil: detach failed - No such file or directory\n", path).unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::NotAttached(_))));
+    let err = detach_status(failed, b"hdiutil: couldn't unmount \"disk9\" - Resource busy\n", path).unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::CommandFailed { .. })));
+    assert_eq!(err.to_string(), "hdiutil failed (exit code 1): hdiutil: couldn't unmount \"disk9\" - Resource busy");
+    assert_eq!(DmgError::from_io_error(&err).and_then(DmgError::exit_code), Some(1));
+    assert!(detach_status(ExitStatus::from_raw(0), b"", path).is_ok());
+    let err = detach_status(ExitStatus::from_raw(9), b"", path).unwrap_err();
+    assert_eq!(err.to_string(), "hdiutil failed (killed by signal 9)");
+}
+
+#[test]
+fn transient_errors() {
+    use std::os::unix::process::ExitStatusExt;
+    let failed = |stderr: &str| -> io::Error {
+        DmgError::CommandFailed { status: ExitStatus::from_raw(1 << 8), stderr: stderr.to_string() }.into()
+    };
+    assert!(is_transient(&failed("hdiutil: attach failed - Resource temporarily unavailable\n")));
+    assert!(!is_transient(&failed("hdiutil: attach failed - image not recognized\n")));
+    assert!(is_transient(&failed("hdiutil: couldn't unmount \"disk4\" - Resource busy\n")));
+    assert!(!is_transient(&failed("hdiutil: detach failed - No such file or directory\n")));
+    assert!(!is_transient(&DmgError::IncorrectPassphrase.into()));
+}
+
+#[test]
+fn streamed_passphrase() {
+    let err = Attach::from_reader(io::empty()).passphrase("hunter2").attach().unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidOptions(_))));
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn folder_sizes() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("small"), b"x").unwrap();
+    std::fs::create_dir(dir.path().join("nested")).unwrap();
+    std::fs::write(dir.path().join("nested").join("large"), vec![0; 5000]).unwrap();
+    std::os::unix::fs::symlink("/nonexistent", dir.path().join("link")).unwrap();
+    // Two directories, one block for the small file and the link each and two for the large one
+    assert_eq!(create::folder_size(dir.path()).unwrap(), 6 * 4096);
+}
+
+#[test]
+fn dry_run_commands() {
+    let cmd = Attach::new("Test.dmg").hidden().mount_temp().command().unwrap();
+    let args: Vec<_> = cmd.get_args().collect();
+    assert_eq!(args[..2], ["attach", "-mountrandom"]);
+    assert_eq!(args[3..], ["-nobrowse", "-plist", "Test.dmg"]);
+
+    let cmd = BlankImage::new(Size::mib(1)).volume_name("Blank").command("Blank").unwrap();
+    let args: Vec<_> = cmd.get_args().collect();
+    assert_eq!(args, ["create", "-size", "1m", "-format", "UDRW", "-volname", "Blank", "Blank.dmg"]);
+    // A dry run leaves the builder as it was, so every later call fails too
+    let blank = BlankImage::new(Size::mib(1)).volume_name("");
+    for _ in 0..2 {
+        let err = blank.command("Blank").unwrap_err();
+        assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidVolumeName { .. })));
+    }
+    let err = blank.create("Blank").unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidVolumeName { .. })));
+
+    let dir = tempfile::tempdir().unwrap();
+    let folder = create::FromFolder::new(dir.path(), create::FolderImageFormat::UDRW).size_to_fit(Size::bytes(0));
+    assert_eq!(folder.command("Folder").unwrap().get_args().nth(6).unwrap(), "4096");
+    std::fs::write(dir.path().join("file"), b"x").unwrap();
+    assert_eq!(folder.command("Folder").unwrap().get_args().nth(6).unwrap(), "8192");
+}
+
+fn args(cmd: &Command) -> Vec<String> {
+    cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+}
+
+#[test]
+fn attach_args() {
+    let attach = Attach::new("Test.dmg")
+        .mount_point("/Volumes/Mine")
+        .read_write()
+        .no_verify()
+        .shadow("Test.shadow")
+        .owners(false)
+        .cache_size(65536)
+        .verbose()
+        .mount_required()
+        .passphrase("hunter2")
+        .extra_arg("-noidme");
+    assert_eq!(args(&attach.build_command(Some(Path::new("Test.shadow")))), [
+        "attach", "-mountpoint", "/Volumes/Mine", "-readwrite", "-noverify", "-shadow", "Test.shadow",
+        "-owners", "off", "-cachesize", "65536", "-verbose", "-mount", "required", "-stdinpass", "-plist",
+        "-noidme", "Test.dmg",
+    ]);
+    assert_eq!(args(&Attach::new("-").section(8, 16).build_command(None)),
+               ["attach", "-section", "8,16", "-plist", "-"]);
+    assert_eq!(args(&Attach::new("Test.dmg").mount_required().build_command(None)),
+               ["attach", "-mount", "required", "-plist", "Test.dmg"]);
+    assert_eq!(args(&Attach::new("Test.dmg").mount_optional().build_command(None)),
+               ["attach", "-mount", "optional", "-plist", "Test.dmg"]);
+    assert_eq!(args(&Attach::new("Test.dmg").mount_suppressed().build_command(None)),
+               ["attach", "-mount", "suppressed", "-plist", "Test.dmg"]);
+    assert_eq!(args(&Attach::new("Test.dmg").no_idme().not_removable().build_command(None)),
+               ["attach", "-noidme", "-notremovable", "-plist", "Test.dmg"]);
+    assert_eq!(args(&Attach::new("Test.dmg").drive_key("system-image", "true").build_command(None)),
+               ["attach", "-drivekey", "system-image=true", "-plist", "Test.dmg"]);
+}
+
+#[test]
+fn create_args() {
+    let folder = create::FromFolder::new("Folder", create::FolderImageFormat::UDZO)
+        .volume_name("Folder")
+        .filesystem(Filesystem::APFS)
+        .source_owners(create::SrcOwners::Off)
+        .mode(0o755)
+        .cross_devices(false)
+        .scrub()
+        .encryption(create::Encryption::Aes256)
+        .passphrase("hunter2")
+        .overwrite();
+    assert_eq!(args(&folder.command("Folder.dmg").unwrap()), [
+        "create", "-srcfolder", "Folder", "-format", "UDZO", "-srcowners", "off", "-mode", "755",
+        "-nocrossdev", "-scrub", "-ov", "-volname", "Folder", "-fs", "APFS", "-encryption", "AES-256",
+        "-stdinpass", "Folder.dmg",
+    ]);
+
+    let blank = BlankImage::new(Size::mib(8))
+        .image_type(create::ImageType::SparseBundle)
+        .band_size(Size::mib(1))
+        .layout(create::Layout::GptSpud)
+        .verbose();
+    assert_eq!(args(&blank.command("Blank").unwrap()), [
+        "create", "-size", "8m", "-type", "SPARSEBUNDLE", "-layout", "GPTSPUD", "-imagekey",
+        "sparse-band-size=2048", "-verbose", "Blank.sparsebundle",
+    ]);
+}
+
+#[test]
+fn convert_args() {
+    let convert = convert::Convert::new("Test.dmg", convert::ConvertFormat::UDSB)
+        .overwrite()
+        .target_image_key("sparse-band-size", "2048")
+        .library("DiskImages.framework");
+    assert_eq!(args(&convert.build_command(Path::new("Test.sparsebundle"))), [
+        "convert", "Test.dmg", "-format", "UDSB", "-ov", "-tgtimagekey", "sparse-band-size=2048", "-library",
+        "DiskImages.framework", "-o", "Test.sparsebundle",
+    ]);
+
+    // The source image stays first, extra arguments go right before the output
+    let convert = convert::Convert::new("Test.dmg", convert::ConvertFormat::UDZO).overwrite().extra_arg("-quiet");
+    assert_eq!(args(&convert.build_command(Path::new("Out.dmg"))),
+               ["convert", "Test.dmg", "-format", "UDZO", "-ov", "-quiet", "-o", "Out.dmg"]);
+}
+
+#[test]
+fn resize_args() {
+    assert_eq!(args(&image::Resize::new("Test.dmg", Size::mib(8)).grow_only().build_command(None)),
+               ["resize", "-size", "8m", "-growonly", "Test.dmg"]);
+    assert_eq!(args(&image::Resize::min("Test.dmg").shrink_only().build_command(None)),
+               ["resize", "-sectors", "min", "-shrinkonly", "Test.dmg"]);
+    assert_eq!(args(&image::Resize::max("Test.dmg").build_command(Some(34359738368))),
+               ["resize", "-sectors", "34359738368", "Test.dmg"]);
+
+    let err = image::Resize::new("Test.dmg", Size::mib(8)).grow_only().shrink_only().resize().unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::InvalidOptions(_))));
+}
+
+#[test]
+fn verify_args() {
+    assert_eq!(args(&image::Verify::new("Test.dmg").quiet().verbose().build_command()),
+               ["verify", "-verbose", "Test.dmg"]);
+    assert_eq!(args(&image::Verify::new("Test.dmg").verbose().quiet().build_command()),
+               ["verify", "-quiet", "Test.dmg"]);
+}
+
+#[test]
+fn detach_args() {
+    let cmd = detach_command("detach", Path::new("/dev/disk4"), true, Verbosity::Quiet);
+    assert_eq!(args(&cmd), ["detach", "-force", "-quiet", "/dev/disk4"]);
+}
+
+#[test]
+fn mount_wait() {
+    let volumes = parse_volumes(&attach_plist()).expect("error parsing");
+    let err = wait_for_mounts(volumes, Duration::from_millis(20)).unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::NotMounted(device)) if device == Path::new("/dev/disk4s1")));
+    let mut volumes = parse_volumes(&attach_plist()).expect("error parsing");
+    for info in &mut volumes.volumes {
+        info.mount_point = env::temp_dir();
+    }
+    assert!(wait_for_mounts(volumes, Duration::ZERO).is_ok());
+}
+
+#[test]
+fn volume_paths() {
+    let info = &parse_volumes(&attach_plist()).expect("error parsing").volumes[0];
+    assert_eq!(info.join("SAMPLE"), Path::new("/Volumes/Test/SAMPLE"));
+    assert_eq!(info.join("/Applications/App.app"), Path::new("/Volumes/Test/Applications/App.app"));
+}
+
+#[test]
+fn detach_failed_checks() {
+    let mut detached = None;
+    let volumes = parse_volumes(&attach_plist()).expect("error parsing");
+    let err = detach_on_error_with(volumes, |volumes| wait_for_mounts(volumes, Duration::ZERO), |device| {
+        detached = Some(device.to_path_buf());
+        Ok(())
+    }).unwrap_err();
+    assert!(matches!(DmgError::from_io_error(&err), Some(DmgError::NotMounted(_))));
+    assert_eq!(detached.as_deref(), Some(Path::new("/dev/disk4")));
+
+    let volumes = parse_volumes(&attach_plist()).expect("error parsing");
+    let info = detach_on_error_with(volumes, first_volume, |_| panic!("detached on success")).unwrap();
+    assert_eq!(info.device, Path::new("/dev/disk4s1"));
+}
+
+#[test]
+fn temp_shadow_path() {
+    let shadow = Attach::new("Test.dmg").shadow_temp().shadow_path(true).unwrap().unwrap();
+    // hdiutil creates the file itself, inside a directory only this process made
+    assert!(!shadow.exists());
+    let dir = shadow.parent().unwrap();
+    assert!(dir.is_dir());
+    std::fs::remove_dir(dir).unwrap();
+}
+
+#[test]
+fn global_lock_feature() {
+    assert_eq!(global_lock().is_some(), cfg!(feature = "global-lock"));
+}